@@ -0,0 +1,542 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::price;
+
+/// Prefix of the versioned API, the same endpoints are also served without it for the bundled UI
+pub const PREFIX: &str = "/api/v1";
+
+/// Error body of every failed API request
+#[derive(Serialize, Debug, Clone)]
+pub struct ApiError {
+    /// Machine readable, e.g. not_found or method_not_allowed
+    pub status: &'static str,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Health {
+    /// ok, or degraded while today's prices fail to download
+    pub status: &'static str,
+    /// Devices are on their failsafe policy
+    pub fallback: bool,
+    pub fetch: price::FetchStatus,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SwitchResult {
+    pub status: &'static str,
+    /// on or off
+    pub action: &'static str,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PricesQuery {
+    /// YYYY-MM-DD, default 30 days before to
+    pub from: Option<String>,
+    /// YYYY-MM-DD, default today
+    pub to: Option<String>,
+    /// Default the configured area
+    pub area: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ActionsQuery {
+    /// YYYY-MM-DD, default 30 days before to
+    pub from: Option<String>,
+    /// YYYY-MM-DD, default today
+    pub to: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SummaryQuery {
+    /// Hours of the cheapest window, 1 to 48, default 1
+    pub window: Option<u64>,
+}
+
+/// A query parameter, for the OpenAPI document
+pub struct Param {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub description: &'static str,
+}
+
+pub struct Route {
+    pub method: &'static str,
+    /// `{name}` matches one path segment
+    pub path: &'static str,
+    pub summary: &'static str,
+    pub content_type: &'static str,
+    /// Component schema of the 200 response, empty for none
+    pub schema: &'static str,
+    pub params: &'static [Param],
+    /// Part of the API, or a static file of the bundled UI
+    pub api: bool,
+}
+
+const JSON: &str = "application/json";
+/// Prometheus text exposition format
+pub const METRICS_TYPE: &str = "text/plain; version=0.0.4";
+
+const fn get(path: &'static str, summary: &'static str, schema: &'static str) -> Route {
+    Route {
+        method: "GET",
+        path,
+        summary,
+        content_type: JSON,
+        schema,
+        params: &[],
+        api: true,
+    }
+}
+
+const fn file(path: &'static str, content_type: &'static str) -> Route {
+    Route {
+        method: "GET",
+        path,
+        summary: "",
+        content_type,
+        schema: "",
+        params: &[],
+        api: false,
+    }
+}
+
+pub const ROUTES: &[Route] = &[
+    get("/health", "Service and price download status", "Health"),
+    Route {
+        content_type: "text/event-stream",
+        ..get(
+            "/events",
+            "Server-Sent Events: device, slot and fetch events as they happen",
+            "",
+        )
+    },
+    get(
+        "/listdevices",
+        "Devices known to the Telldus gateway",
+        "TelldusDevices",
+    ),
+    get("/data", "The whole application state", "AppState"),
+    get("/config", "The running config", "Config"),
+    get(
+        "/devices",
+        "Devices with their state and trigger prices",
+        "Devices",
+    ),
+    get("/stats", "Energy, cost and savings per device", "Stats"),
+    Route {
+        params: &[Param {
+            name: "window",
+            kind: "integer",
+            description: "Hours of the cheapest window, 1 to 48, default 1",
+        }],
+        ..get("/summary", "Current, next and cheapest prices", "Summary")
+    },
+    Route {
+        params: &[
+            Param {
+                name: "from",
+                kind: "string",
+                description: "YYYY-MM-DD, default 30 days before to",
+            },
+            Param {
+                name: "to",
+                kind: "string",
+                description: "YYYY-MM-DD, default today",
+            },
+            Param {
+                name: "area",
+                kind: "string",
+                description: "Price area, default the configured area",
+            },
+        ],
+        ..get(
+            "/prices",
+            "Daily summaries of the stored price history",
+            "PriceHistory",
+        )
+    },
+    get(
+        "/plan",
+        "Predicted On/Off timeline of every device over today and tomorrow",
+        "Plan",
+    ),
+    Route {
+        content_type: METRICS_TYPE,
+        ..get(
+            "/metrics",
+            "Prices, device states and failure counters for Prometheus",
+            "",
+        )
+    },
+    Route {
+        params: &[
+            Param {
+                name: "from",
+                kind: "string",
+                description: "YYYY-MM-DD, default 30 days before to",
+            },
+            Param {
+                name: "to",
+                kind: "string",
+                description: "YYYY-MM-DD, default today",
+            },
+        ],
+        ..get("/actions", "Recorded device state changes", "Actions")
+    },
+    get("/today", "Today's prices as downloaded", "Prices"),
+    get(
+        "/tomorrow",
+        "Tomorrow's prices as downloaded, empty until published",
+        "Prices",
+    ),
+    Route {
+        method: "POST",
+        ..get(
+            "/switchon/{name}",
+            "Switch a device On, requires webui_toggle",
+            "SwitchResult",
+        )
+    },
+    Route {
+        method: "POST",
+        ..get(
+            "/switchoff/{name}",
+            "Switch a device Off, requires webui_toggle",
+            "SwitchResult",
+        )
+    },
+    get("/openapi.json", "This document", ""),
+    file("/", "text/html"),
+    file("/pricecontrol.js", "application/javascript"),
+    file("/chart.js", "application/javascript"),
+    file("/listdevices.htm", "text/html"),
+];
+
+impl Route {
+    pub fn matches(&self, path: &str) -> bool {
+        match self.path.split_once("{name}") {
+            Some((prefix, _)) => path
+                .strip_prefix(prefix)
+                .is_some_and(|name| !name.is_empty() && !name.contains('/')),
+            None => self.path == path,
+        }
+    }
+
+    /// HEAD is answered like GET, without the body
+    pub fn allows(&self, method: &str) -> bool {
+        self.method == method || (self.method == "GET" && method == "HEAD")
+    }
+}
+
+/// The OpenAPI 3 description of the API, generated from ROUTES
+pub fn openapi() -> Value {
+    let mut paths = Map::new();
+
+    for route in ROUTES.iter().filter(|r| r.api) {
+        let mut parameters: Vec<Value> = route
+            .params
+            .iter()
+            .map(|p| {
+                json!({
+                    "name": p.name,
+                    "in": "query",
+                    "required": false,
+                    "description": p.description,
+                    "schema": { "type": p.kind },
+                })
+            })
+            .collect();
+        if route.path.contains("{name}") {
+            parameters.push(json!({
+                "name": "name",
+                "in": "path",
+                "required": true,
+                "description": "Device name",
+                "schema": { "type": "string" },
+            }));
+        }
+
+        let content = match route.schema {
+            "" => json!({}),
+            schema => json!({ "schema": reference(schema) }),
+        };
+        let operation = json!({
+            "summary": route.summary,
+            "parameters": parameters,
+            "responses": {
+                "200": {
+                    "description": "OK",
+                    "content": { route.content_type: content },
+                },
+                "default": {
+                    "description": "Error",
+                    "content": {
+                        JSON: { "schema": reference("Error") },
+                    },
+                },
+            },
+        });
+
+        let entry = paths
+            .entry(format!("{}{}", PREFIX, route.path))
+            .or_insert_with(|| json!({}));
+        entry[route.method.to_lowercase()] = operation;
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+        },
+        "paths": paths,
+        "components": { "schemas": schemas() },
+    })
+}
+
+fn reference(schema: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", schema) })
+}
+
+/// An object with all its properties always present, Options as null
+fn object(properties: Value) -> Value {
+    let required: Vec<String> = properties
+        .as_object()
+        .map(|p| p.keys().cloned().collect())
+        .unwrap_or_default();
+    json!({ "type": "object", "required": required, "properties": properties })
+}
+
+/// An object described by its main properties only, it has more
+fn open(description: &str, properties: Value) -> Value {
+    json!({
+        "type": "object",
+        "description": description,
+        "properties": properties,
+        "additionalProperties": true,
+    })
+}
+
+/// The response bodies, written to match the Serialize output of the types they describe
+fn schemas() -> Value {
+    let string = json!({ "type": "string" });
+    let number = json!({ "type": "number" });
+    let integer = json!({ "type": "integer" });
+    let boolean = json!({ "type": "boolean" });
+    let time = json!({ "type": "string", "format": "date-time" });
+    let date = json!({ "type": "string", "format": "date" });
+    let nullable = |schema: &Value| {
+        let mut schema = schema.clone();
+        schema["nullable"] = json!(true);
+        schema
+    };
+    let nullable_ref = |name: &str| json!({ "allOf": [reference(name)], "nullable": true });
+    let array = |items: Value| json!({ "type": "array", "items": items });
+    let map = |values: Value| json!({ "type": "object", "additionalProperties": values });
+
+    json!({
+        "Error": object(json!({ "status": string, "error": string })),
+        "Health": object(json!({
+            "status": { "type": "string", "enum": ["ok", "degraded"] },
+            "fallback": boolean,
+            "fetch": reference("FetchStatus"),
+        })),
+        "FetchStatus": object(json!({
+            "last_success": nullable(&time),
+            "last_error": nullable(&string),
+            "failing": map(reference("DayFetch")),
+        })),
+        "DayFetch": object(json!({
+            "failures": integer,
+            "last_error": string,
+            "next_retry": time,
+        })),
+        "SwitchResult": object(json!({
+            "status": string,
+            "action": { "type": "string", "enum": ["on", "off"] },
+            "name": string,
+        })),
+        "TelldusDevices": {
+            "type": "object",
+            "description": "As answered by the Telldus gateway",
+        },
+        "State": { "type": "string", "enum": ["Unknown", "On", "Off"] },
+        "Devices": object(json!({
+            "device": array(reference("Device")),
+            "group": array(reference("Group")),
+        })),
+        "Device": open(
+            "A device from devices.toml with its runtime state, see devices.toml for the rest",
+            json!({
+                "name": string,
+                "mode": { "type": "string", "enum": ["Unknown", "Price", "Ratio", "Average", "Rule"] },
+                "state": reference("State"),
+                "today_trigger_price": number,
+                "tomorrow_trigger_price": number,
+                "suppressed": string,
+                "shed_minutes": number,
+                "priority": integer,
+                "power_kw": number,
+            }),
+        ),
+        "Group": object(json!({
+            "name": string,
+            "devices": array(string.clone()),
+            "max_on": integer,
+        })),
+        "Config": open(
+            "The settings of pricecontrol.toml, see it for the rest",
+            json!({
+                "api": string,
+                "area": string,
+                "currency": string,
+                "interval": integer,
+                "webui_port": integer,
+                "webui_toggle": boolean,
+                "dry_run": boolean,
+            }),
+        ),
+        "Prices": {
+            "type": "array",
+            "description": "Price slots as downloaded, the price under the configured currency key",
+            "items": open(
+                "One price slot",
+                json!({ "time_start": time, "time_end": time }),
+            ),
+        },
+        "AppState": object(json!({
+            "config": reference("Config"),
+            "devices": reference("Devices"),
+            "todays_spot_prices": reference("Prices"),
+            "tomorrows_spot_prices": reference("Prices"),
+            "stats": reference("Stats"),
+            "fallback": boolean,
+        })),
+        "Stats": object(json!({ "devices": map(reference("DeviceStats")) })),
+        "DeviceStats": object(json!({
+            "days": map(reference("Usage")),
+            "months": map(reference("Usage")),
+            "intervals": array(reference("Interval")),
+        })),
+        "Usage": object(json!({
+            "minutes": number,
+            "kwh": number,
+            "spot_cost": number,
+            "total_cost": number,
+            "average_cost": number,
+            "savings": number,
+        })),
+        "Interval": object(json!({
+            "slot": time,
+            "start": time,
+            "end": time,
+            "spot": number,
+            "total": number,
+        })),
+        "Summary": object(json!({
+            "currency": string,
+            "current": nullable_ref("SlotPrice"),
+            "next": nullable_ref("SlotPrice"),
+            "today": nullable_ref("DayPrices"),
+            "cheapest_window": nullable_ref("Window"),
+            "window_hours": integer,
+            "tomorrow_available": boolean,
+        })),
+        "SlotPrice": object(json!({
+            "start": time,
+            "end": time,
+            "spot": number,
+            "total": number,
+        })),
+        "DayPrices": object(json!({ "min": number, "max": number, "avg": number })),
+        "Window": object(json!({
+            "start": time,
+            "end": time,
+            "spot": number,
+            "total": number,
+        })),
+        "PriceHistory": array(reference("DaySummary")),
+        "DaySummary": object(json!({
+            "date": date,
+            "min": nullable(&number),
+            "avg": nullable(&number),
+            "max": nullable(&number),
+            "hours": {
+                "type": "array",
+                "minItems": 24,
+                "maxItems": 24,
+                "items": nullable(&number),
+            },
+        })),
+        "Plan": object(json!({
+            "currency": string,
+            "devices": array(reference("DevicePlan")),
+        })),
+        "DevicePlan": object(json!({
+            "name": string,
+            "spans": array(reference("Span")),
+        })),
+        "Span": object(json!({
+            "start": time,
+            "end": time,
+            "state": reference("State"),
+            "reason": string,
+            "spot": number,
+        })),
+        "Actions": array(reference("ActionRecord")),
+        "ActionRecord": object(json!({
+            "time": time,
+            "device": string,
+            "from": reference("State"),
+            "to": reference("State"),
+            "reason": string,
+            "spot": nullable(&number),
+        })),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every `$ref` under `value`
+    fn refs(value: &Value, out: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(r)) = map.get("$ref") {
+                    out.push(r.trim_start_matches("#/components/schemas/").to_string());
+                }
+                map.values().for_each(|v| refs(v, out));
+            }
+            Value::Array(items) => items.iter().for_each(|v| refs(v, out)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn every_schema_reference_resolves() {
+        let doc = openapi();
+        let mut names = vec![];
+        refs(&doc, &mut names);
+
+        for route in ROUTES.iter().filter(|r| r.api && r.content_type == JSON) {
+            if route.path != "/openapi.json" {
+                assert!(names.contains(&route.schema.to_string()), "{}", route.path);
+            }
+        }
+        for name in names {
+            assert!(
+                doc["components"]["schemas"].get(&name).is_some(),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::device_model;
+use crate::rules;
 use crate::structs;
 use std::fs;
 use std::fs::OpenOptions;
@@ -10,6 +11,7 @@ use std::path::PathBuf;
 pub fn read_config_from_file(path: &PathBuf) -> Result<structs::Config, structs::ConfigError> {
     let contents = fs::read_to_string(path)?;
     let config = toml::from_str::<structs::Config>(&contents)?;
+    config.validate()?;
     Ok(config)
 }
 
@@ -24,12 +26,55 @@ pub fn config_path() -> PathBuf {
     PathBuf::from("pricecontrol.toml")
 }
 
-/// Loads the devices
+/// Loads the devices, checks their references and parses their rules
 pub fn read_devices_from_file(
     path: &PathBuf,
 ) -> Result<device_model::Devices, structs::DeviceError> {
     let contents = fs::read_to_string(path)?;
-    Ok(toml::from_str(&contents)?)
+    let mut devices: device_model::Devices = toml::from_str(&contents)?;
+
+    let names: Vec<String> = devices.device.iter().map(|d| d.name.clone()).collect();
+
+    for group in &devices.group {
+        if let Some(unknown) = group.devices.iter().find(|n| !names.contains(n)) {
+            return Err(structs::DeviceError::UnknownDevice(
+                format!("group {}", group.name),
+                unknown.clone(),
+            ));
+        }
+    }
+
+    for device in devices.device.iter_mut() {
+        if let Some(unknown) = device.requires.iter().find(|n| !names.contains(n)) {
+            return Err(structs::DeviceError::UnknownDevice(
+                format!("device {}", device.name),
+                unknown.clone(),
+            ));
+        }
+
+        if let Some(hour) = device.failsafe_schedule.iter().find(|&&h| h > 23) {
+            return Err(structs::DeviceError::Invalid(
+                device.name.clone(),
+                format!("failsafe_schedule hour {}, must be 0-23", hour),
+            ));
+        }
+
+        if device.mode != device_model::Mode::Rule {
+            continue;
+        }
+        let rule = rules::parse(&device.on_when)
+            .map_err(|e| structs::DeviceError::Rule(device.name.clone(), e))?;
+
+        if let Some(unknown) = rule.device_names().into_iter().find(|n| !names.contains(n)) {
+            return Err(structs::DeviceError::Rule(
+                device.name.clone(),
+                rules::RuleError::UnknownDevice(unknown),
+            ));
+        }
+        device.rule = Some(rule);
+    }
+
+    Ok(devices)
 }
 
 /// Return a static embedded file for release builds
@@ -65,3 +110,112 @@ fn write_new_file_from_string(filename: &str, contents: &str) -> io::Result<()>
 
     file.write_all(contents.as_bytes())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify;
+    use crate::structs::tests::config;
+    use std::net::SocketAddr;
+
+    fn read(devices: &str) -> Result<device_model::Devices, structs::DeviceError> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(devices.as_bytes()).unwrap();
+        read_devices_from_file(&file.path().to_path_buf())
+    }
+
+    #[test]
+    fn failsafe_schedule_hours_are_checked() {
+        let ok = read("[[device]]\nname = \"heater\"\nfailsafe_schedule = [0, 6, 23]\n");
+        assert_eq!(ok.unwrap().device[0].failsafe_schedule, vec![0, 6, 23]);
+
+        let err = read("[[device]]\nname = \"heater\"\nfailsafe_schedule = [6, 24]\n");
+        assert!(matches!(
+            err,
+            Err(structs::DeviceError::Invalid(name, what)) if name == "heater" && what.contains("24")
+        ));
+    }
+
+    #[test]
+    fn webui_addresses_take_the_port_unless_given() {
+        let mut config = config();
+        config.webui_port = 8088;
+        config.webui_bind = vec![
+            "0.0.0.0".into(),
+            "192.168.0.10:8443".into(),
+            "::1".into(),
+            "[::1]".into(),
+            "[fe80::1]:9000".into(),
+        ];
+        let addrs: Vec<SocketAddr> = [
+            "0.0.0.0:8088",
+            "192.168.0.10:8443",
+            "[::1]:8088",
+            "[::1]:8088",
+            "[fe80::1]:9000",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        assert_eq!(config.webui_addrs().unwrap(), addrs);
+
+        config.webui_bind = vec![];
+        assert_eq!(config.webui_addrs().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn invalid_webui_addresses_are_rejected() {
+        for bind in ["localhost", "192.168.0.300", "[::1", "10.0.0.1:99999", ""] {
+            let mut config = config();
+            config.webui_bind = vec![bind.into()];
+            assert!(
+                matches!(config.validate(), Err(structs::ConfigError::Invalid(_))),
+                "{}",
+                bind
+            );
+        }
+
+        let mut config = config();
+        config.webui_port = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn tomorrow_publish_must_be_a_time_of_day() {
+        let mut config = config();
+        for (publish, time) in [("13:00", (13, 0)), ("7:45", (7, 45)), ("23:59", (23, 59))] {
+            config.tomorrow_publish = publish.into();
+            let parsed = config.publish_time().unwrap();
+            assert_eq!((parsed.hour(), parsed.minute()), time);
+        }
+        for publish in ["24:00", "13:60", "1300", "noon", ""] {
+            config.tomorrow_publish = publish.into();
+            assert!(config.validate().is_err(), "{}", publish);
+        }
+    }
+
+    #[test]
+    fn smtp_password_needs_encryption() {
+        let mut config = config();
+        config.notifier = vec![toml::from_str(
+            r#"
+            kind = "smtp"
+            server = "mail.example.com"
+            security = "none"
+            username = "rpc"
+            from = "rpc@example.com"
+            to = ["me@example.com"]
+            "#,
+        )
+        .unwrap()];
+        assert!(matches!(
+            config.validate(),
+            Err(structs::ConfigError::Invalid(_))
+        ));
+
+        if let notify::Sink::Smtp(smtp) = &mut config.notifier[0].sink {
+            smtp.username.clear();
+        }
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,372 @@
+use log::info;
+use std::collections::HashSet;
+
+use time::{OffsetDateTime, Time};
+
+use crate::device_model::{Device, Devices, State};
+use crate::structs;
+
+/// A wanted state and the reason for it, None keeps the current state
+pub type Decision = Option<(State, String)>;
+
+/// Resolve groups, dependencies and the power limit after every device has made its own decision.
+/// Devices are admitted On by priority, then config order, each together with the devices it
+/// requires. A device that would break a limit stays Off and gets the reason in `suppressed`.
+pub fn resolve(
+    devices: &mut Devices,
+    decisions: Vec<Decision>,
+    config: &structs::Config,
+    now: OffsetDateTime,
+) -> Vec<Decision> {
+    let wants_on: Vec<bool> = devices
+        .device
+        .iter()
+        .zip(&decisions)
+        .map(|(device, decision)| match decision {
+            Some((state, _)) => *state == State::On,
+            None => device.state == State::On,
+        })
+        .collect();
+
+    let mut on: HashSet<usize> = HashSet::new();
+    let mut required_by: Vec<Option<String>> = vec![None; devices.device.len()];
+    let mut suppressed: Vec<String> = vec![String::new(); devices.device.len()];
+
+    // Stable sort, equal priorities keep config order
+    let mut order: Vec<usize> = (0..devices.device.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(devices.device[i].priority));
+
+    for i in order {
+        if !wants_on[i] || on.contains(&i) {
+            continue;
+        }
+
+        let unit = requirements(devices, i);
+        match broken_group(devices, &on, &unit).or_else(|| over_power(devices, &on, &unit, config))
+        {
+            Some(reason) => suppressed[i] = reason,
+            None => {
+                for &j in &unit {
+                    if j != i && !wants_on[j] && required_by[j].is_none() {
+                        required_by[j] = Some(devices.device[i].name.clone());
+                    }
+                }
+                on.extend(unit);
+            }
+        }
+    }
+
+    let mut resolved = vec![];
+    for (i, decision) in decisions.into_iter().enumerate() {
+        let device = &mut devices.device[i];
+        track_shed(device, now);
+        device.suppressed = suppressed[i].clone();
+
+        resolved.push(if let Some(by) = &required_by[i] {
+            Some((State::On, format!("Required by {}", by)))
+        } else if !suppressed[i].is_empty() {
+            Some((State::Off, suppressed[i].clone()))
+        } else {
+            decision
+        });
+    }
+    resolved
+}
+
+/// Log devices that became suppressed since `before`, the suppressed reasons of the last round
+pub fn log_suppressed(before: &[String], devices: &Devices) {
+    for (device, before) in devices.device.iter().zip(before) {
+        if !device.suppressed.is_empty() && device.suppressed != *before {
+            info!("{}: Suppressed - {}", device.name, device.suppressed);
+        }
+    }
+}
+
+/// The device and every device it requires, directly or indirectly
+fn requirements(devices: &Devices, index: usize) -> Vec<usize> {
+    let mut unit = vec![index];
+    let mut next = 0;
+
+    while next < unit.len() {
+        for name in &devices.device[unit[next]].requires {
+            if let Some(j) = devices.device.iter().position(|d| &d.name == name) {
+                if !unit.contains(&j) {
+                    unit.push(j);
+                }
+            }
+        }
+        next += 1;
+    }
+    unit
+}
+
+/// The reason the unit can't be On together with the devices already On, if any
+fn broken_group(devices: &Devices, on: &HashSet<usize>, unit: &[usize]) -> Option<String> {
+    devices.group.iter().find_map(|group| {
+        let count = devices
+            .device
+            .iter()
+            .enumerate()
+            .filter(|(j, d)| {
+                (on.contains(j) || unit.contains(j)) && group.devices.contains(&d.name)
+            })
+            .count();
+
+        (count > group.max_on).then(|| format!("Group {} allows {} On", group.name, group.max_on))
+    })
+}
+
+/// The reason the unit would exceed max_power_kw together with the devices already On, if any
+fn over_power(
+    devices: &Devices,
+    on: &HashSet<usize>,
+    unit: &[usize],
+    config: &structs::Config,
+) -> Option<String> {
+    if config.max_power_kw <= 0.0 {
+        return None;
+    }
+
+    let power: f64 = devices
+        .device
+        .iter()
+        .enumerate()
+        .filter(|(j, _)| on.contains(j) || unit.contains(j))
+        .map(|(_, d)| d.power_kw)
+        .sum();
+
+    (power > config.max_power_kw).then(|| format!("Power limit {} kW", config.max_power_kw))
+}
+
+/// Count the time since the last round as shed if the device was suppressed during it.
+/// Only the part of it today counts, so a replan or a round after midnight adds nothing twice.
+fn track_shed(device: &mut Device, now: OffsetDateTime) {
+    let today = Some(now.date());
+    if device.shed_day != today {
+        device.shed_day = today;
+        device.shed_minutes = 0.0;
+    }
+
+    if let Some(at) = device.shed_at.filter(|_| !device.suppressed.is_empty()) {
+        let from = at.max(now.replace_time(Time::MIDNIGHT));
+        if now > from {
+            device.shed_minutes += (now - from).as_seconds_f64() / 60.0;
+        }
+    }
+    device.shed_at = Some(now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::tests::{config, midnight};
+    use time::Duration;
+
+    fn devices(toml: &str) -> Devices {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn on(name: &str) -> Decision {
+        Some((State::On, format!("{} wants On", name)))
+    }
+
+    /// The resolved states, None where the device keeps its state
+    fn states(decisions: &[Decision]) -> Vec<Option<State>> {
+        decisions
+            .iter()
+            .map(|d| d.as_ref().map(|(s, _)| s.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn groups_keep_the_first_device_on() {
+        let mut devices = devices(
+            r#"
+            [[device]]
+            name = "sauna"
+            [[device]]
+            name = "ev"
+            [[device]]
+            name = "lamp"
+            [[group]]
+            name = "fuse"
+            devices = ["sauna", "ev"]
+            "#,
+        );
+        let resolved = resolve(
+            &mut devices,
+            vec![on("sauna"), on("ev"), on("lamp")],
+            &config(),
+            midnight(),
+        );
+
+        assert_eq!(
+            states(&resolved),
+            [Some(State::On), Some(State::Off), Some(State::On)]
+        );
+        assert_eq!(devices.device[1].suppressed, "Group fuse allows 1 On");
+        assert_eq!(devices.device[0].suppressed, "");
+    }
+
+    #[test]
+    fn priority_goes_first() {
+        let mut devices = devices(
+            r#"
+            [[device]]
+            name = "sauna"
+            [[device]]
+            name = "ev"
+            priority = 1
+            [[group]]
+            name = "fuse"
+            devices = ["sauna", "ev"]
+            "#,
+        );
+        let resolved = resolve(
+            &mut devices,
+            vec![on("sauna"), on("ev")],
+            &config(),
+            midnight(),
+        );
+        assert_eq!(states(&resolved), [Some(State::Off), Some(State::On)]);
+    }
+
+    #[test]
+    fn required_devices_follow() {
+        let mut devices = devices(
+            r#"
+            [[device]]
+            name = "heater"
+            requires = ["pump"]
+            [[device]]
+            name = "pump"
+            requires = ["valve"]
+            [[device]]
+            name = "valve"
+            "#,
+        );
+        let resolved = resolve(
+            &mut devices,
+            vec![on("heater"), Some((State::Off, "Price".into())), None],
+            &config(),
+            midnight(),
+        );
+
+        assert_eq!(
+            resolved[1],
+            Some((State::On, "Required by heater".to_string()))
+        );
+        assert_eq!(
+            resolved[2],
+            Some((State::On, "Required by heater".to_string()))
+        );
+    }
+
+    #[test]
+    fn a_device_stays_off_when_its_requirement_cant_be_on() {
+        let mut devices = devices(
+            r#"
+            [[device]]
+            name = "sauna"
+            [[device]]
+            name = "heater"
+            requires = ["pump"]
+            [[device]]
+            name = "pump"
+            [[group]]
+            name = "fuse"
+            devices = ["sauna", "pump"]
+            "#,
+        );
+        let resolved = resolve(
+            &mut devices,
+            vec![on("sauna"), on("heater"), None],
+            &config(),
+            midnight(),
+        );
+        assert_eq!(states(&resolved), [Some(State::On), Some(State::Off), None]);
+    }
+
+    #[test]
+    fn power_limit_by_priority() {
+        let mut devices = devices(
+            r#"
+            [[device]]
+            name = "sauna"
+            power_kw = 6.0
+            [[device]]
+            name = "ev"
+            power_kw = 7.0
+            priority = 2
+            [[device]]
+            name = "heater"
+            power_kw = 3.0
+            priority = 1
+            "#,
+        );
+        let mut config = config();
+        config.max_power_kw = 11.0;
+        let resolved = resolve(
+            &mut devices,
+            vec![on("sauna"), on("ev"), on("heater")],
+            &config,
+            midnight(),
+        );
+
+        assert_eq!(
+            states(&resolved),
+            [Some(State::Off), Some(State::On), Some(State::On)]
+        );
+        assert_eq!(devices.device[0].suppressed, "Power limit 11 kW");
+
+        // Devices already On count against the limit when they keep their state
+        devices.device[1].state = State::On;
+        let resolved = resolve(
+            &mut devices,
+            vec![None, on("sauna"), None],
+            &config,
+            midnight(),
+        );
+        assert_eq!(states(&resolved), [None, Some(State::On), None]);
+    }
+
+    #[test]
+    fn shed_counts_the_time_suppressed_once() {
+        let mut devices = devices(
+            r#"
+            [[device]]
+            name = "sauna"
+            [[device]]
+            name = "ev"
+            [[group]]
+            name = "fuse"
+            devices = ["sauna", "ev"]
+            "#,
+        );
+        let config = config();
+        let at = |minutes| midnight() + Duration::minutes(minutes);
+        let both = || vec![on("sauna"), on("ev")];
+
+        resolve(&mut devices, both(), &config, at(600));
+        assert_eq!(devices.device[1].shed_minutes, 0.0);
+
+        resolve(&mut devices, both(), &config, at(610));
+        assert_eq!(devices.device[1].shed_minutes, 10.0);
+
+        // A replan right after the round adds only the time since
+        resolve(&mut devices, both(), &config, at(611));
+        assert_eq!(devices.device[1].shed_minutes, 11.0);
+
+        // Suppressed until 620, then not until 630, nothing is added for that time
+        resolve(&mut devices, vec![on("sauna"), None], &config, at(620));
+        assert_eq!(devices.device[1].shed_minutes, 20.0);
+        resolve(&mut devices, both(), &config, at(630));
+        assert_eq!(devices.device[1].shed_minutes, 20.0);
+
+        // Only the time after midnight counts for the new day
+        resolve(&mut devices, both(), &config, at(24 * 60 - 5));
+        resolve(&mut devices, both(), &config, at(24 * 60 + 5));
+        assert_eq!(devices.device[1].shed_minutes, 5.0);
+        assert_eq!(devices.device[1].shed_day, Some(at(24 * 60).date()));
+    }
+}
@@ -5,13 +5,30 @@ use std::process::Command;
 use std::thread;
 use std::time::Duration;
 use thiserror::Error;
+use time::{Date, OffsetDateTime};
 
-use crate::structs;
+use crate::{metrics, notify, price, rules, structs};
 
 /// Vector of devices from config file
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Devices {
     pub device: Vec<Device>,
+    #[serde(default)]
+    pub group: Vec<Group>,
+}
+
+/// A group of devices that may not all be On at the same time
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Group {
+    pub name: String,
+    pub devices: Vec<String>,
+    /// At most this many devices in the group On, 1 = mutual exclusion
+    #[serde(default = "default_max_on")]
+    pub max_on: usize,
+}
+
+fn default_max_on() -> usize {
+    1
 }
 
 /// Devices in the device vector
@@ -24,6 +41,66 @@ pub struct Device {
     pub ratio: f64,
     #[serde(default)]
     pub price: f64,
+    /// Average mode: on below this share of the reference price, 0.8 = 80%
+    #[serde(default)]
+    pub relative: f64,
+    #[serde(default)]
+    pub reference: Reference,
+    /// Number of days for the Rolling reference, including today
+    #[serde(default)]
+    pub reference_days: u64,
+    /// Rule mode: on while this expression is true, see rules.rs
+    #[serde(default)]
+    pub on_when: String,
+    #[serde(skip)]
+    pub rule: Option<rules::Expr>,
+    /// Rolling horizon in hours for Ratio mode and the Average and Median references of
+    /// Average mode, 0 plans each calendar day on its own
+    #[serde(default)]
+    pub horizon: u64,
+    /// Ratio mode budget in minutes per day (or per horizon), overrides ratio when set
+    #[serde(default)]
+    pub run_minutes: u64,
+    /// Decide on hourly average prices, switching at most once an hour
+    #[serde(default)]
+    pub hourly: bool,
+    /// Minimum minutes to stay On once switched On
+    #[serde(default)]
+    pub min_run: u64,
+    /// Force On while the price is negative
+    #[serde(default)]
+    pub negative_on: bool,
+    /// Force Off while the price is negative, e.g. solar export
+    #[serde(default)]
+    pub negative_off: bool,
+    /// Higher priority devices are kept On first under groups and the power limit
+    #[serde(default)]
+    pub priority: i64,
+    /// Power draw when On, for the max_power_kw limit
+    #[serde(default)]
+    pub power_kw: f64,
+    /// Devices that must be On whenever this device is On
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Why a group kept this device Off, empty if it wasn't
+    #[serde(default, skip_deserializing)]
+    pub suppressed: String,
+    /// What to do after failsafe_grace minutes without valid prices
+    #[serde(default)]
+    pub failsafe: Failsafe,
+    /// Hours 0-23 to be On with the Schedule failsafe
+    #[serde(default)]
+    pub failsafe_schedule: Vec<u8>,
+    #[serde(skip)]
+    pub switched_at: Option<OffsetDateTime>,
+    /// Minutes this device wanted On but was suppressed today
+    #[serde(default, skip_deserializing)]
+    pub shed_minutes: f64,
+    #[serde(skip)]
+    pub shed_day: Option<Date>,
+    /// The last round shed time was counted at, the next round counts the time since
+    #[serde(skip)]
+    pub shed_at: Option<OffsetDateTime>,
     #[serde(default)]
     pub today_trigger_price: f64,
     #[serde(default)]
@@ -40,6 +117,9 @@ pub struct Device {
     pub script_on: String,
     #[serde(default)]
     pub script_off: String,
+    /// Log the Telldus requests and scripts instead of running them
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 impl Device {
@@ -53,6 +133,48 @@ impl Device {
         self.telldus_action(command_request, config)
     }
 
+    /// Only log what would be switched, by this device's or the global dry_run
+    pub fn is_dry_run(&self, config: &structs::Config) -> bool {
+        self.dry_run || config.dry_run
+    }
+
+    /// The share of the slots the device should run in Ratio mode.
+    /// Minutes lost to load shedding are added when the slots start on the day they were lost,
+    /// so they are made up in later slots of that day and not again tomorrow.
+    pub fn budget_ratio(&self, slots: &[structs::Slot]) -> f64 {
+        let total: f64 = slots.iter().map(structs::Slot::minutes).sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let minutes = if self.run_minutes == 0 {
+            self.ratio * total
+        } else {
+            self.run_minutes as f64
+        };
+        let shed = match slots.first() {
+            Some(slot) if Some(slot.start.date()) == self.shed_day => self.shed_minutes,
+            _ => 0.0,
+        };
+        ((minutes + shed) / total).clamp(0.0, 1.0)
+    }
+
+    /// The slots this device decides on, hourly averages if so configured
+    pub fn slots(&self, slots: &[structs::Slot]) -> Vec<structs::Slot> {
+        if self.hourly {
+            price::hourly(slots)
+        } else {
+            slots.to_vec()
+        }
+    }
+
+    /// Whether min_run still holds the device On at `now`
+    pub fn held_on(&self, now: OffsetDateTime) -> bool {
+        match (self.state == State::On, self.switched_at) {
+            (true, Some(at)) => now - at < time::Duration::minutes(self.min_run as i64),
+            _ => false,
+        }
+    }
+
     pub fn switch_on(&self, config: &structs::Config) -> Result<State, ActionError> {
         self.change_state(config, State::On)
     }
@@ -62,6 +184,11 @@ impl Device {
     }
 
     fn change_state(&self, config: &structs::Config, action: State) -> Result<State, ActionError> {
+        if self.is_dry_run(config) {
+            self.dry_run_state(config, &action);
+            return Ok(action);
+        }
+
         if self.telldus {
             info!(
                 "{}: Telldus switching {:?} device {}",
@@ -76,7 +203,15 @@ impl Device {
 
             match reply {
                 Ok(r) => debug!("Telldus reply: {r:?}"),
-                Err(e) => error!("Telldus: {e:?}"),
+                Err(e) => {
+                    error!("Telldus: {e:?}");
+                    metrics::telldus_failed(&self.name);
+                    notify::send(notify::Event::SwitchFailed {
+                        device: self.name.clone(),
+                        action: format!("{:?}", action),
+                        error: e.to_string(),
+                    });
+                }
             }
         }
 
@@ -97,6 +232,27 @@ impl Device {
         Ok(action)
     }
 
+    /// Log the Telldus request and script a switch would make
+    fn dry_run_state(&self, config: &structs::Config, action: &State) {
+        let (command, script) = match action {
+            State::On => ("turnOn", &self.script_on),
+            State::Off => ("turnOff", &self.script_off),
+            _ => return,
+        };
+        if self.telldus {
+            info!(
+                "{}: Dry run, would request http://{}/api/device/{}?id={}",
+                self.name, config.telldus_ip, command, self.telldus_id
+            );
+        }
+        if !script.is_empty() {
+            info!(
+                "{}: Dry run, would execute {:?} script: {}",
+                self.name, action, script
+            );
+        }
+    }
+
     fn telldus_action(
         &self,
         command_request: String,
@@ -137,19 +293,45 @@ impl Device {
             }
         };
 
-        // Move the script string into the closure
-        thread::spawn(move || {
-            #[cfg(unix)]
-            let _ = Command::new("sh").arg(script).spawn();
-
-            #[cfg(windows)]
-            let _ = Command::new("cmd").arg("/C").arg(script).spawn();
-        });
+        spawn_script(&self.name, script);
 
         Ok(())
     }
 }
 
+/// Run a script in the background, `source` names the device or event in logs and metrics
+pub fn spawn_script(source: &str, script: String) {
+    let source = source.to_string();
+    // Move the script string into the closure
+    thread::spawn(move || {
+        #[cfg(unix)]
+        let status = Command::new("sh").arg(&script).status();
+
+        #[cfg(windows)]
+        let status = Command::new("cmd").arg("/C").arg(&script).status();
+
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                warn!("{}: Script {} exited with {}", source, script, status);
+                metrics::script_failed(&source);
+                notify::send(notify::Event::ScriptFailed {
+                    error: format!("{} exited with {}", script, status),
+                    source,
+                });
+            }
+            Err(e) => {
+                error!("{}: Could not run script {}: {e}", source, script);
+                metrics::script_failed(&source);
+                notify::send(notify::Event::ScriptFailed {
+                    error: format!("Could not run {}: {e}", script),
+                    source,
+                });
+            }
+        }
+    });
+}
+
 /// State of devices
 #[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
 pub enum State {
@@ -166,6 +348,32 @@ pub enum Mode {
     Unknown,
     Price,
     Ratio,
+    Average,
+    Rule,
+}
+
+/// Failsafe policy when there are no valid prices
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub enum Failsafe {
+    /// Keep the current state
+    #[default]
+    Stay,
+    On,
+    Off,
+    /// On during the hours in failsafe_schedule, Off otherwise
+    Schedule,
+}
+
+/// Reference price for Average mode
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub enum Reference {
+    /// Today's average price
+    #[default]
+    Average,
+    /// Today's median price
+    Median,
+    /// Average over the last reference_days days
+    Rolling,
 }
 
 #[derive(Debug, Error)]
@@ -179,3 +387,84 @@ pub enum ActionError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::tests::{config, midnight, slots, slots_from};
+    use std::fs;
+    use std::net::TcpListener;
+
+    #[test]
+    fn shed_minutes_only_extend_their_own_day() {
+        let mut devices: Devices = toml::from_str(
+            r#"
+            [[device]]
+            name = "heater"
+            mode = "Ratio"
+            run_minutes = 120
+            "#,
+        )
+        .unwrap();
+        let device = &mut devices.device[0];
+        device.shed_minutes = 60.0;
+        device.shed_day = Some(midnight().date());
+
+        let today = slots(60, &[1.0; 24]);
+        let tomorrow = slots_from(today[23].end, 60, &[1.0; 24]);
+        assert_eq!(device.budget_ratio(&today), 180.0 / 1440.0);
+        assert_eq!(device.budget_ratio(&tomorrow), 120.0 / 1440.0);
+        // A horizon from this afternoon starts today
+        assert_eq!(device.budget_ratio(&today[12..]), 180.0 / 720.0);
+    }
+
+    #[test]
+    fn dry_run_switches_without_telldus_or_scripts() {
+        let telldus = TcpListener::bind("127.0.0.1:0").unwrap();
+        telldus.set_nonblocking(true).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let ran = dir.path().join("ran");
+        let script = dir.path().join("on.sh");
+        fs::write(&script, format!("touch '{}'", ran.display())).unwrap();
+
+        let mut config = config();
+        config.telldus_ip = telldus.local_addr().unwrap().to_string();
+        let mut devices: Devices = toml::from_str(&format!(
+            r#"
+            [[device]]
+            name = "heater"
+            mode = "Price"
+            telldus = true
+            telldus_id = "1"
+            script_on = "{}"
+            dry_run = true
+            "#,
+            script.display()
+        ))
+        .unwrap();
+        let device = &mut devices.device[0];
+
+        // The device's own flag and the global one
+        assert_eq!(device.switch_on(&config).unwrap(), State::On);
+        device.dry_run = false;
+        config.dry_run = true;
+        assert!(device.is_dry_run(&config));
+        assert_eq!(device.switch_on(&config).unwrap(), State::On);
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(telldus.accept().is_err());
+        assert!(!ran.exists());
+
+        // The same script runs once dry run is off
+        config.dry_run = false;
+        device.telldus = false;
+        device.switch_on(&config).unwrap();
+        for _ in 0..50 {
+            if ran.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(ran.exists());
+    }
+}
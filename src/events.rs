@@ -0,0 +1,57 @@
+use serde::Serialize;
+use std::cell::RefCell;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+
+/// One sender per connected /events client
+static SUBSCRIBERS: Mutex<Vec<SyncSender<String>>> = Mutex::new(Vec::new());
+
+/// Events queued for a client that isn't reading, beyond this it is dropped and reconnects
+const BACKLOG: usize = 64;
+
+thread_local! {
+    /// Events held back until this thread has updated the app state
+    static DEFERRED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Receive every event published from now on, formatted as Server-Sent Events
+pub fn subscribe() -> Receiver<String> {
+    let (tx, rx) = sync_channel(BACKLOG);
+    if let Ok(mut subscribers) = SUBSCRIBERS.lock() {
+        subscribers.push(tx);
+    }
+    rx
+}
+
+/// Push an event to every client, dropping the ones that have gone away or fallen behind
+pub fn publish<T: Serialize>(event: &str, data: &T) {
+    if let Some(message) = format(event, data) {
+        send(message);
+    }
+}
+
+/// Publish an event once this thread calls `flush`, for changes a client would otherwise
+/// refetch before they are visible in the app state
+pub fn defer<T: Serialize>(event: &str, data: &T) {
+    if let Some(message) = format(event, data) {
+        DEFERRED.with(|d| d.borrow_mut().push(message));
+    }
+}
+
+/// Publish the events this thread has deferred
+pub fn flush() {
+    for message in DEFERRED.with(|d| d.take()) {
+        send(message);
+    }
+}
+
+fn format<T: Serialize>(event: &str, data: &T) -> Option<String> {
+    let data = serde_json::to_string(data).ok()?;
+    Some(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+fn send(message: String) {
+    if let Ok(mut subscribers) = SUBSCRIBERS.lock() {
+        subscribers.retain(|tx| tx.try_send(message.clone()).is_ok());
+    }
+}
@@ -1,22 +1,92 @@
 use anyhow::Result;
 use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
 use std::thread;
 use std::time::Duration as TimeDuration;
-use time::{Date, Duration, OffsetDateTime};
+use time::{Date, Duration, OffsetDateTime, Time};
 
-use crate::{config, device_model, price, structs, telldus};
+use crate::{
+    config, constraints, device_model, history, notify, plan, price, rules, structs, telldus,
+};
+
+/// Spawn a thread that fetches tomorrow's prices once they are published.
+/// It sleeps until tomorrow_publish, then polls every tomorrow_poll minutes until the prices
+/// arrive, and signals `replan` so the main loop re-plans every device right away.
+pub fn get_tomorrow_thread(config: structs::Config, replan: Sender<()>) {
+    // Checked when the config was loaded
+    let publish = config
+        .publish_time()
+        .unwrap_or(Time::from_hms(13, 0, 0).unwrap());
+    let poll = TimeDuration::from_secs(config.tomorrow_poll.max(1) * 60);
 
-/// Spawn a thread that loops just to get tomorrow's data at a lower tick rate.
-pub fn get_tomorrow_thread(config: structs::Config) {
     thread::spawn(move || loop {
+        price::prune_cache(&config);
+
         let tomorrow = make_tomorrow(&config);
-        if let Err(err) = price::read_price_data(tomorrow) {
-            debug!("Failed to download tomorrow’s data: {}", err);
+        if price::try_load_local(&tomorrow).is_ok() {
+            // Wait for the next day's publication
+            thread::sleep(until(publish, 1).min(TimeDuration::from_secs(3600)));
+            continue;
+        }
+
+        let wait = until(publish, 0);
+        if !wait.is_zero() {
+            debug!(
+                "Tomorrow's prices are published in {} min",
+                wait.as_secs() / 60
+            );
+            thread::sleep(wait.min(TimeDuration::from_secs(3600)));
+            continue;
+        }
+
+        let date = tomorrow.date;
+        // The polls are already spaced out, the backoff only applies between them
+        match price::poll_price_data(tomorrow, poll) {
+            Ok(data) => {
+                info!("Tomorrow's prices have arrived, re-planning");
+                tomorrow_event(date, &data, &config);
+                let _ = replan.send(());
+            }
+            Err(err) => {
+                debug!("Failed to download tomorrow’s data: {}", err);
+                thread::sleep(poll);
+            }
         }
-        thread::sleep(TimeDuration::from_secs(3600));
     });
 }
 
+/// Time left until the publish time, `days` days from today, zero if it has passed
+fn until(publish: Time, days: i64) -> TimeDuration {
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    let target = (now.date() + Duration::days(days))
+        .with_time(publish)
+        .assume_offset(now.offset());
+    (target - now).try_into().unwrap_or(TimeDuration::ZERO)
+}
+
+/// Run the tomorrow script, webhook and notifiers when tomorrow's prices arrive
+fn tomorrow_event(date: Date, data: &serde_json::Value, config: &structs::Config) {
+    let slots = price::slots(data, &config.currency);
+    notify::send(notify::Event::TomorrowPrices {
+        date: date.to_string(),
+        average: price::average_of(&slots),
+        min: slots.iter().map(|s| s.price).reduce(f64::min),
+        max: slots.iter().map(|s| s.price).reduce(f64::max),
+        currency: config.currency.clone(),
+    });
+
+    if !config.tomorrow_script.is_empty() && config.dry_run {
+        info!(
+            "Dry run, would execute tomorrow script: {}",
+            config.tomorrow_script
+        );
+    } else if !config.tomorrow_script.is_empty() {
+        info!("Executing tomorrow script: {}", config.tomorrow_script);
+        device_model::spawn_script("tomorrow", config.tomorrow_script.clone());
+    }
+}
+
 /// Check args for cli
 pub fn check_args(args: &[String], config_result: &Result<structs::Config, structs::ConfigError>) {
     if args.contains(&"-h".into()) || args.contains(&"--help".into()) {
@@ -24,6 +94,9 @@ pub fn check_args(args: &[String], config_result: &Result<structs::Config, struc
         println!("Usage: {} [OPTION]\n", env!("CARGO_PKG_NAME"));
         println!("    --telldus-list        List Telldus devices (requires config file)");
         println!("    --generate-config     Create a default config file");
+        println!("    --clear-cache         Remove cached price files");
+        println!("    --plan                Print when each device will run today and tomorrow");
+        println!("    --dry-run             Log what devices would do instead of switching them");
         println!("-h  --help                This help");
         println!("-v  --version             Version information");
         std::process::exit(0);
@@ -48,6 +121,64 @@ pub fn check_args(args: &[String], config_result: &Result<structs::Config, struc
         std::process::exit(0);
     }
 
+    if args.contains(&"--clear-cache".into()) {
+        let config = match config_result {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                eprintln!("The argument --clear-cache needs a working config file. Error loading config: {e}");
+                std::process::exit(2);
+            }
+        };
+
+        match price::clear_cache(config) {
+            Ok(n) => println!(
+                "Removed {} cached price files from {}",
+                n,
+                price::cache_dir(config).display()
+            ),
+            Err(e) => {
+                eprintln!("Error clearing the cache: {e}");
+                std::process::exit(3);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    if args.contains(&"--plan".into()) {
+        let config = match config_result {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                eprintln!(
+                    "The argument --plan needs a working config file. Error loading config: {e}"
+                );
+                std::process::exit(2);
+            }
+        };
+        let devices = match config::read_devices_from_file(&config::config_path()) {
+            Ok(devices) => devices,
+            Err(e) => {
+                eprintln!("Error loading devices: {e}");
+                std::process::exit(2);
+            }
+        };
+        let today = match price::read_price_data(make_today(config)) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error getting today's prices: {e}");
+                std::process::exit(3);
+            }
+        };
+        // Tomorrow is left out until it is published
+        let tomorrow =
+            price::read_price_data(make_tomorrow(config)).unwrap_or_else(|_| serde_json::json!([]));
+
+        print!(
+            "{}",
+            plan::table(&plan::plan(&today, &tomorrow, &devices, config))
+        );
+        std::process::exit(0);
+    }
+
     if args.contains(&"--telldus-list".into()) {
         let config = match config_result {
             Ok(cfg) => cfg,
@@ -73,48 +204,77 @@ pub fn check_args(args: &[String], config_result: &Result<structs::Config, struc
 
 /// Make a today-instance
 pub fn make_today(config: &structs::Config) -> structs::Day {
-    let today: Date = OffsetDateTime::now_local().unwrap().date();
-    let today_str = format!(
-        "{}/{:02}-{:02}",
-        today.year(),
-        today.month() as u8,
-        today.day()
-    );
-    structs::Day {
-        date: today,
-        url: format!("{}{}_{}.json", config.api, today_str, config.area),
-        file: format!(
-            "{}-{:02}-{:02}_{}.json",
-            today.year(),
-            today.month() as u8,
-            today.day(),
-            config.area
-        ),
-    }
+    make_day(
+        config,
+        OffsetDateTime::now_local()
+            .unwrap_or_else(|_| OffsetDateTime::now_utc())
+            .date(),
+    )
 }
 
 /// Make a tomorrow-instance
 pub fn make_tomorrow(config: &structs::Config) -> structs::Day {
-    let tomorrow: Date = OffsetDateTime::now_local().unwrap().date() + Duration::days(1);
-    let tomorrow_str = format!(
+    make_day(
+        config,
+        OffsetDateTime::now_local()
+            .unwrap_or_else(|_| OffsetDateTime::now_utc())
+            .date()
+            + Duration::days(1),
+    )
+}
+
+/// Make a day-instance for any date
+pub fn make_day(config: &structs::Config, date: Date) -> structs::Day {
+    let date_str = format!(
         "{}/{:02}-{:02}",
-        tomorrow.year(),
-        tomorrow.month() as u8,
-        tomorrow.day()
+        date.year(),
+        date.month() as u8,
+        date.day()
     );
+    let mut file = price::cache_dir(config);
+    file.push(format!(
+        "{}_{}-{:02}-{:02}_{}_{}.json",
+        price::provider_key(&config.api),
+        date.year(),
+        date.month() as u8,
+        date.day(),
+        config.area,
+        config.currency
+    ));
+
     structs::Day {
-        date: tomorrow,
-        url: format!("{}{}_{}.json", config.api, tomorrow_str, config.area),
-        file: format!(
-            "{}-{:02}-{:02}_{}.json",
-            tomorrow.year(),
-            tomorrow.month() as u8,
-            tomorrow.day(),
-            config.area
-        ),
+        date,
+        area: config.area.clone(),
+        currency: config.currency.clone(),
+        urls: std::iter::once(&config.api)
+            .chain(&config.api_fallback)
+            .map(|api| format!("{}{}_{}.json", api, date_str, config.area))
+            .collect(),
+        file,
     }
 }
 
+/// Average spot price over `today` and the previous days found in history or the local cache
+fn rolling_average(config: &structs::Config, days: u64, today: Date) -> Option<f64> {
+    let (price_minutes, minutes) = (0..days.max(1) as i64)
+        .map(|n| today - Duration::days(n))
+        .filter_map(|date| {
+            // Days cached before history was kept are read once
+            history::day_total(&config.area, &config.currency, date, || {
+                let json = price::try_load_local(&make_day(config, date)).ok()?;
+                Some(history::DayTotal::of(&price::slots(
+                    &json,
+                    &config.currency,
+                )))
+            })
+        })
+        .fold((0.0, 0.0), |(p, m), day| {
+            (p + day.price_minutes, m + day.minutes)
+        });
+
+    (minutes > 0.0).then(|| price_minutes / minutes)
+}
+
 /// The main loop
 pub fn logic_loop(
     today_spot_prices: &serde_json::Value,
@@ -122,11 +282,13 @@ pub fn logic_loop(
     mut devices: device_model::Devices,
     config: &structs::Config,
 ) -> Result<device_model::Devices, device_model::ActionError> {
-    let price = price::current_price(today_spot_prices, &config.currency);
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    let price = price::price_at(today_spot_prices, &config.currency, now);
 
     let avg_price = price::average_price(today_spot_prices, &config.currency).unwrap();
 
     if let Some(p) = price {
+        notify::check_price(p);
         debug!("Current spot price: {:.2} {}", p, &config.currency);
         debug!(
             "With fees and VAT:  {:.2} {}",
@@ -139,56 +301,23 @@ pub fn logic_loop(
 
     debug!("Average spot price: {:.2} {}", avg_price, &config.currency);
 
-    for device in devices.device.iter_mut() {
-        if device.mode == device_model::Mode::Price {
-            device.today_trigger_price = device.price;
-            device.tomorrow_trigger_price = device.price;
-            if (device.state != device_model::State::On
-                && device.today_trigger_price > price.unwrap_or_default())
-                || (device.force_update && device.today_trigger_price > price.unwrap_or_default())
-            {
-                info!(
-                    "{}: {:?} mode - Changing state to On",
-                    device.name, device.mode
-                );
-                device.state = device.switch_on(config)?;
-            } else if (device.state != device_model::State::Off
-                && device.today_trigger_price < price.unwrap_or_default())
-                || (device.force_update && device.today_trigger_price < price.unwrap_or_default())
-            {
-                info!(
-                    "{}: {:?} mode - Changing state to Off",
-                    device.name, device.mode,
-                );
-                device.state = device.switch_on(config)?;
-            }
-        }
+    let suppressed: Vec<String> = devices
+        .device
+        .iter()
+        .map(|d| d.suppressed.clone())
+        .collect();
+    let decisions = decide(
+        today_spot_prices,
+        tomorrow_spot_prices,
+        &mut devices,
+        config,
+        now,
+    );
+    constraints::log_suppressed(&suppressed, &devices);
 
-        if device.mode == device_model::Mode::Ratio {
-            device.today_trigger_price =
-                price::ratio_price(today_spot_prices, &config.currency, device.ratio).unwrap();
-            device.tomorrow_trigger_price =
-                price::ratio_price(tomorrow_spot_prices, &config.currency, device.ratio)
-                    .unwrap_or(0.0);
-            if (device.state != device_model::State::On
-                && device.today_trigger_price > price.unwrap_or_default())
-                || (device.force_update && device.today_trigger_price > price.unwrap_or_default())
-            {
-                info!(
-                    "{}: {:?} mode - Changing state to On",
-                    device.name, device.mode
-                );
-                device.state = device.switch_on(config)?;
-            } else if (device.state != device_model::State::Off
-                && device.today_trigger_price < price.unwrap_or_default())
-                || (device.force_update && device.today_trigger_price < price.unwrap_or_default())
-            {
-                info!(
-                    "{}: {:?} mode - Changing state to Off",
-                    device.name, device.mode
-                );
-                device.state = device.switch_off(config)?;
-            }
+    for (device, decision) in devices.device.iter_mut().zip(decisions) {
+        if let Some((state, reason)) = decision {
+            apply_state(device, state, &reason, price, config)?;
         }
 
         debug!(
@@ -204,3 +333,419 @@ pub fn logic_loop(
 
     Ok(devices)
 }
+
+/// One control round at `now` without switching anything: sets the trigger prices and returns
+/// each device's decision after groups, dependencies and the power limit.
+pub fn decide(
+    today_spot_prices: &serde_json::Value,
+    tomorrow_spot_prices: &serde_json::Value,
+    devices: &mut device_model::Devices,
+    config: &structs::Config,
+    now: OffsetDateTime,
+) -> Vec<constraints::Decision> {
+    let price = price::price_at(today_spot_prices, &config.currency, now);
+    let negative = price.is_some_and(|p| price::is_negative(p, config));
+
+    let today_slots = price::slots(today_spot_prices, &config.currency);
+    let tomorrow_slots = price::slots(tomorrow_spot_prices, &config.currency);
+
+    // Rules see the states as they were at the start of this round
+    let states: HashMap<String, bool> = devices
+        .device
+        .iter()
+        .map(|d| (d.name.clone(), d.state == device_model::State::On))
+        .collect();
+    let tomorrow_known = !tomorrow_slots.is_empty();
+    let mut decisions: Vec<constraints::Decision> = vec![];
+
+    for device in devices.device.iter_mut() {
+        let today = device.slots(&today_slots);
+        let tomorrow = device.slots(&tomorrow_slots);
+
+        match device.mode {
+            device_model::Mode::Price => {
+                device.today_trigger_price = device.price;
+                device.tomorrow_trigger_price = device.price;
+            }
+            device_model::Mode::Ratio if device.horizon > 0 => {
+                let upcoming = price::horizon_slots(&today, &tomorrow, device.horizon, now);
+                device.today_trigger_price =
+                    price::ratio_of(&upcoming, device.budget_ratio(&upcoming)).unwrap_or(0.0);
+                device.tomorrow_trigger_price = device.today_trigger_price;
+            }
+            device_model::Mode::Ratio => {
+                device.today_trigger_price =
+                    price::ratio_of(&today, device.budget_ratio(&today)).unwrap_or(0.0);
+                device.tomorrow_trigger_price =
+                    price::ratio_of(&tomorrow, device.budget_ratio(&tomorrow)).unwrap_or(0.0);
+            }
+            device_model::Mode::Average => {
+                // With a horizon the reference is taken over the upcoming hours instead
+                let upcoming;
+                let (today, tomorrow) = if device.horizon > 0 {
+                    upcoming = price::horizon_slots(&today, &tomorrow, device.horizon, now);
+                    (&upcoming[..], &upcoming[..])
+                } else {
+                    (&today[..], &tomorrow[..])
+                };
+                let (today_reference, tomorrow_reference) = match device.reference {
+                    device_model::Reference::Average => {
+                        (price::average_of(today), price::average_of(tomorrow))
+                    }
+                    device_model::Reference::Median => {
+                        (price::ratio_of(today, 0.5), price::ratio_of(tomorrow, 0.5))
+                    }
+                    device_model::Reference::Rolling => {
+                        let rolling = rolling_average(config, device.reference_days, now.date());
+                        (rolling, rolling)
+                    }
+                };
+                device.today_trigger_price = today_reference.unwrap_or(0.0) * device.relative;
+                device.tomorrow_trigger_price = tomorrow_reference.unwrap_or(0.0) * device.relative;
+            }
+            device_model::Mode::Rule => {
+                device.today_trigger_price = 0.0;
+                device.tomorrow_trigger_price = 0.0;
+            }
+            device_model::Mode::Unknown => {}
+        }
+
+        let decision = if negative && (device.negative_on || device.negative_off) {
+            let state = if device.negative_on {
+                device_model::State::On
+            } else {
+                device_model::State::Off
+            };
+            Some((state, "Negative price".to_string()))
+        } else if device.mode == device_model::Mode::Rule {
+            let ctx = rule_context(config, &today, tomorrow_known, &states, now);
+            rule_decision(device, &ctx, now).map(|state| (state, "Rule mode".to_string()))
+        } else if device.mode != device_model::Mode::Unknown {
+            price_decision(device, price::slot_price_at(&today, now), now)
+                .map(|state| (state, format!("{:?} mode", device.mode)))
+        } else {
+            None
+        };
+        decisions.push(decision);
+    }
+
+    // Groups, dependencies and the power limit get the final say
+    constraints::resolve(devices, decisions, config, now)
+}
+
+/// Apply each device's failsafe policy while there are no valid prices
+pub fn failsafe_loop(
+    mut devices: device_model::Devices,
+    config: &structs::Config,
+) -> Result<device_model::Devices, device_model::ActionError> {
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    let hour = now.hour();
+
+    let decisions: Vec<constraints::Decision> = devices
+        .device
+        .iter()
+        .map(|device| {
+            let state = match device.failsafe {
+                device_model::Failsafe::Stay => return None,
+                device_model::Failsafe::On => device_model::State::On,
+                device_model::Failsafe::Off => device_model::State::Off,
+                device_model::Failsafe::Schedule if device.failsafe_schedule.contains(&hour) => {
+                    device_model::State::On
+                }
+                device_model::Failsafe::Schedule => device_model::State::Off,
+            };
+            Some((state, "Failsafe".to_string()))
+        })
+        .collect();
+
+    // The fuse still has its limits without prices
+    let suppressed: Vec<String> = devices
+        .device
+        .iter()
+        .map(|d| d.suppressed.clone())
+        .collect();
+    let decisions = constraints::resolve(&mut devices, decisions, config, now);
+    constraints::log_suppressed(&suppressed, &devices);
+
+    for (device, decision) in devices.device.iter_mut().zip(decisions) {
+        if let Some((state, reason)) = decision {
+            apply_state(device, state, &reason, None, config)?;
+        }
+    }
+
+    Ok(devices)
+}
+
+/// The state a device wants from its trigger price, None keeps the current state
+fn price_decision(
+    device: &device_model::Device,
+    price: Option<f64>,
+    now: OffsetDateTime,
+) -> Option<device_model::State> {
+    let price = price.unwrap_or_default();
+
+    if device.today_trigger_price > price {
+        Some(device_model::State::On)
+    } else if device.today_trigger_price < price {
+        if device.held_on(now) {
+            debug!("{}: Held On by min_run", device.name);
+            return None;
+        }
+        Some(device_model::State::Off)
+    } else {
+        None
+    }
+}
+
+/// The state a device wants from its rule, None keeps the current state
+fn rule_decision(
+    device: &device_model::Device,
+    ctx: &rules::Context,
+    now: OffsetDateTime,
+) -> Option<device_model::State> {
+    match device.rule.as_ref()?.eval(ctx) {
+        Some(rules::Value::Bool(true)) => Some(device_model::State::On),
+        Some(rules::Value::Bool(false)) if device.held_on(now) => {
+            debug!("{}: Held On by min_run", device.name);
+            None
+        }
+        Some(rules::Value::Bool(false)) => Some(device_model::State::Off),
+        _ => {
+            debug!("{}: Rule has no value right now", device.name);
+            None
+        }
+    }
+}
+
+/// Values of the rule variables at `now`
+fn rule_context(
+    config: &structs::Config,
+    today: &[structs::Slot],
+    tomorrow_known: bool,
+    states: &HashMap<String, bool>,
+    now: OffsetDateTime,
+) -> rules::Context {
+    let mut ctx = rules::Context {
+        devices: states.clone(),
+        ..Default::default()
+    };
+    ctx.bools.insert("tomorrow", tomorrow_known);
+
+    ctx.numbers.insert("hour", now.hour() as f64);
+    ctx.numbers.insert("minute", now.minute() as f64);
+    ctx.numbers
+        .insert("weekday", now.weekday().number_from_monday() as f64);
+
+    if let Some(average) = price::average_of(today) {
+        ctx.numbers.insert("average", average);
+    }
+
+    if let Some(spot) = price::slot_price_at(today, now) {
+        let rank = 1 + today.iter().filter(|s| s.price < spot).count();
+        let total_minutes: f64 = today.iter().map(structs::Slot::minutes).sum();
+        let cheaper_minutes: f64 = today
+            .iter()
+            .filter(|s| s.price < spot)
+            .map(structs::Slot::minutes)
+            .sum();
+
+        ctx.numbers.insert("spot", spot);
+        ctx.numbers
+            .insert("total", price::total_price(spot, config));
+        ctx.numbers.insert("rank", rank as f64);
+        ctx.numbers
+            .insert("percentile", 100.0 * cheaper_minutes / total_minutes);
+        ctx.bools
+            .insert("negative", price::is_negative(spot, config));
+    }
+
+    ctx
+}
+
+/// Switch a device unless it is already in the state, force_update always switches
+fn apply_state(
+    device: &mut device_model::Device,
+    state: device_model::State,
+    reason: &str,
+    spot: Option<f64>,
+    config: &structs::Config,
+) -> Result<(), device_model::ActionError> {
+    if device.state == state && !device.force_update {
+        return Ok(());
+    }
+
+    info!(
+        "{}: {} - Changing state to {:?}",
+        device.name, reason, state
+    );
+    let previous = device.state.clone();
+
+    device.state = match state {
+        device_model::State::On => device.switch_on(config)?,
+        _ => device.switch_off(config)?,
+    };
+
+    if device.state != previous {
+        device.switched_at = Some(OffsetDateTime::now_utc());
+        let reason = if device.is_dry_run(config) {
+            format!("{} (dry run)", reason)
+        } else {
+            reason.to_string()
+        };
+        history::record_action(&device.name, &previous, &device.state, &reason, spot);
+    }
+
+    Ok(())
+}
+
+/// Run the negative price hooks and notifiers at the start and end of a negative period
+pub fn negative_event(active: bool, price: Option<f64>, config: &structs::Config) {
+    notify::send(notify::Event::NegativePrice {
+        active,
+        price,
+        currency: config.currency.clone(),
+    });
+
+    let (event, script) = if active {
+        ("start", &config.negative_script_start)
+    } else {
+        ("end", &config.negative_script_end)
+    };
+    info!("Negative price period {}", event);
+
+    if !script.is_empty() && config.dry_run {
+        info!(
+            "Dry run, would execute negative price {} script: {}",
+            event, script
+        );
+    } else if !script.is_empty() {
+        info!("Executing negative price {} script: {}", event, script);
+        device_model::spawn_script("negative", script.clone());
+    }
+
+    if !config.negative_webhook.is_empty() && config.dry_run {
+        info!(
+            "Dry run, would POST negative price {} to {}",
+            event, config.negative_webhook
+        );
+    } else if !config.negative_webhook.is_empty() {
+        let body = serde_json::json!({
+            "event": format!("negative_price_{}", event),
+            "price": price,
+            "currency": config.currency,
+        });
+        post_webhook(config.negative_webhook.clone(), body, "Negative price");
+    }
+}
+
+/// POST a JSON event in the background
+fn post_webhook(url: String, body: serde_json::Value, what: &'static str) {
+    thread::spawn(move || {
+        let result = reqwest::blocking::Client::builder()
+            .timeout(TimeDuration::from_secs(10))
+            .build()
+            .and_then(|client| client.post(&url).json(&body).send());
+        if let Err(e) = result {
+            warn!("{what} webhook failed: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::tests::{config, json_of, midnight, slots, slots_from};
+    use std::fs;
+
+    #[test]
+    fn rolling_average_weighs_stored_and_cached_days() {
+        let cache = tempfile::tempdir().unwrap();
+        let mut config = config();
+        config.area = "ROLLING".to_string();
+        config.cache_dir = cache.path().display().to_string();
+
+        let today = midnight().date();
+        let day_prices = |days_ago: i64, minutes: i64, price: f64| {
+            let start = midnight() - Duration::days(days_ago);
+            json_of(&slots_from(
+                start,
+                minutes,
+                &vec![price; (24 * 60 / minutes) as usize],
+            ))
+        };
+
+        // Today and yesterday were downloaded, the day before is only in the cache
+        history::record_prices(&make_day(&config, today), &day_prices(0, 15, 1.0));
+        history::record_prices(
+            &make_day(&config, today - Duration::days(1)),
+            &day_prices(1, 60, 2.0),
+        );
+        let cached = make_day(&config, today - Duration::days(2));
+        fs::create_dir_all(cached.file.parent().unwrap()).unwrap();
+        fs::write(&cached.file, day_prices(2, 60, 6.0).to_string()).unwrap();
+
+        assert_eq!(rolling_average(&config, 1, today), Some(1.0));
+        assert_eq!(rolling_average(&config, 2, today), Some(1.5));
+        assert_eq!(rolling_average(&config, 3, today), Some(3.0));
+        // Missing days are left out rather than counted as zero
+        assert_eq!(rolling_average(&config, 7, today), Some(3.0));
+        // A later download of a day replaces it
+        history::record_prices(&make_day(&config, today), &day_prices(0, 60, 4.0));
+        assert_eq!(rolling_average(&config, 3, today), Some(4.0));
+    }
+
+    #[test]
+    fn negative_prices_override_the_mode() {
+        let mut devices: device_model::Devices = toml::from_str(
+            r#"
+            [[device]]
+            name = "boiler"
+            mode = "Price"
+            price = -5.0
+            negative_on = true
+
+            [[device]]
+            name = "export"
+            mode = "Price"
+            price = 5.0
+            negative_off = true
+
+            [[device]]
+            name = "heater"
+            mode = "Price"
+            price = 5.0
+            "#,
+        )
+        .unwrap();
+        let mut config = config();
+        let today = json_of(&slots(60, &[-1.0, 2.0]));
+        let tomorrow = json_of(&[]);
+        let negative = Some((device_model::State::On, "Negative price".to_string()));
+
+        let decisions = decide(&today, &tomorrow, &mut devices, &config, midnight());
+        assert_eq!(
+            decisions,
+            [
+                negative.clone(),
+                Some((device_model::State::Off, "Negative price".to_string())),
+                Some((device_model::State::On, "Price mode".to_string())),
+            ]
+        );
+
+        // Above negative_price every device follows its mode
+        let later = midnight() + Duration::hours(1);
+        let decisions = decide(&today, &tomorrow, &mut devices, &config, later);
+        assert!(decisions
+            .iter()
+            .all(|d| d.as_ref().is_some_and(|(_, reason)| reason == "Price mode")));
+
+        // With fees the total price isn't negative
+        config.negative_total = true;
+        config.grid_fee = 1.5;
+        let decisions = decide(&today, &tomorrow, &mut devices, &config, midnight());
+        assert_ne!(decisions[0], negative);
+        config.negative_total = false;
+        let decisions = decide(&today, &tomorrow, &mut devices, &config, midnight());
+        assert_eq!(decisions[0], negative);
+    }
+}
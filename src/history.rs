@@ -0,0 +1,412 @@
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use time::format_description::well_known::{Iso8601, Rfc3339};
+use time::{Date, Duration, OffsetDateTime};
+
+use crate::{device_model, events, metrics, price, structs};
+
+const PRICES_FILE: &str = "prices.jsonl";
+const ACTIONS_FILE: &str = "actions.jsonl";
+
+/// Append-only history of downloaded prices and device state changes, one JSON object per line
+struct History {
+    dir: PathBuf,
+    retention_days: u64,
+    /// Serializes writes and remembers when old lines were last pruned
+    last_prune: Mutex<Option<Date>>,
+}
+
+static HISTORY: OnceLock<History> = OnceLock::new();
+
+/// Price totals of each stored day, so rolling averages don't read prices.jsonl every round
+struct Daily {
+    currency: String,
+    /// None for days that were looked up and not found
+    days: HashMap<(String, Date), Option<DayTotal>>,
+}
+
+static DAILY: Mutex<Option<Daily>> = Mutex::new(None);
+
+/// The slot-length weighted price sum and the minutes of a day, for averages over several days
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DayTotal {
+    pub price_minutes: f64,
+    pub minutes: f64,
+}
+
+impl DayTotal {
+    pub fn of(slots: &[structs::Slot]) -> Self {
+        DayTotal {
+            price_minutes: slots.iter().map(|s| s.price * s.minutes()).sum(),
+            minutes: slots.iter().map(structs::Slot::minutes).sum(),
+        }
+    }
+}
+
+/// A downloaded price series
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PriceRecord {
+    pub date: String,
+    pub area: String,
+    pub downloaded: String,
+    pub prices: Value,
+}
+
+/// A device state change and why it happened
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActionRecord {
+    pub time: String,
+    pub device: String,
+    pub from: device_model::State,
+    pub to: device_model::State,
+    pub reason: String,
+    pub spot: Option<f64>,
+}
+
+/// The directory for history, from config or the system data dir
+pub fn data_dir(config: &structs::Config) -> PathBuf {
+    if !config.data_dir.is_empty() {
+        return PathBuf::from(&config.data_dir);
+    }
+    match dirs::data_dir() {
+        Some(mut dir) => {
+            dir.push("pricecontrol");
+            dir
+        }
+        None => PathBuf::from("history"),
+    }
+}
+
+/// Set up the history store, called once at startup
+pub fn init(config: &structs::Config) {
+    let dir = data_dir(config);
+    if let Err(e) = fs::create_dir_all(&dir) {
+        warn!("Could not create history dir {}: {e}", dir.display());
+        return;
+    }
+    info!("History dir: {}", dir.display());
+
+    let _ = HISTORY.set(History {
+        dir,
+        retention_days: config.history_days,
+        last_prune: Mutex::new(None),
+    });
+}
+
+/// Store a downloaded price series
+pub fn record_prices(day: &structs::Day, prices: &Value) {
+    let record = PriceRecord {
+        date: day.date.to_string(),
+        area: day.area.clone(),
+        downloaded: now_string(),
+        prices: prices.clone(),
+    };
+    with_daily(&day.currency, |daily| {
+        let total = DayTotal::of(&price::slots(prices, &day.currency));
+        daily.insert((day.area.clone(), day.date), Some(total));
+    });
+    append(PRICES_FILE, &record);
+}
+
+/// The price total of a stored day, or of `load` for days that were never stored.
+/// Both are remembered, a later download of the day replaces them.
+pub fn day_total(
+    area: &str,
+    currency: &str,
+    date: Date,
+    load: impl FnOnce() -> Option<DayTotal>,
+) -> Option<DayTotal> {
+    with_daily(currency, |daily| {
+        *daily.entry((area.to_string(), date)).or_insert_with(load)
+    })
+    .flatten()
+}
+
+/// Run `f` on the daily totals, loaded from the stored prices the first time they are needed
+fn with_daily<T>(
+    currency: &str,
+    f: impl FnOnce(&mut HashMap<(String, Date), Option<DayTotal>>) -> T,
+) -> Option<T> {
+    let mut daily = DAILY.lock().ok()?;
+    if daily.as_ref().is_none_or(|d| d.currency != currency) {
+        let mut days = HashMap::new();
+        // Later downloads of a date replace earlier ones
+        for record in read::<PriceRecord>(PRICES_FILE) {
+            if let Ok(date) = Date::parse(&record.date, &Iso8601::DATE) {
+                let total = DayTotal::of(&price::slots(&record.prices, currency));
+                days.insert((record.area, date), Some(total));
+            }
+        }
+        *daily = Some(Daily {
+            currency: currency.to_string(),
+            days,
+        });
+    }
+    daily.as_mut().map(|d| f(&mut d.days))
+}
+
+/// Store a device state change and push it to the web UI
+pub fn record_action(
+    device: &str,
+    from: &device_model::State,
+    to: &device_model::State,
+    reason: &str,
+    spot: Option<f64>,
+) {
+    let record = ActionRecord {
+        time: now_string(),
+        device: device.to_string(),
+        from: from.clone(),
+        to: to.clone(),
+        reason: reason.to_string(),
+        spot,
+    };
+    // Sent once the caller has stored the new state, see events::flush
+    events::defer("device", &record);
+    metrics::switched(device);
+    append(ACTIONS_FILE, &record);
+}
+
+/// The latest stored price series for each date in the range, in date order
+pub fn prices(from: Date, to: Date, area: &str) -> Vec<PriceRecord> {
+    let mut records: Vec<PriceRecord> = read(PRICES_FILE)
+        .into_iter()
+        .filter(|r: &PriceRecord| r.area == area)
+        .filter(|r| Date::parse(&r.date, &Iso8601::DATE).is_ok_and(|d| d >= from && d <= to))
+        .collect();
+
+    // Later downloads of the same date replace earlier ones
+    records.reverse();
+    let mut seen = HashSet::new();
+    records.retain(|r| seen.insert(r.date.clone()));
+    records.sort_by(|a, b| a.date.cmp(&b.date));
+    records
+}
+
+/// Min, average, max and hourly averages of one stored day
+#[derive(Serialize, Debug, Clone)]
+pub struct DaySummary {
+    pub date: String,
+    pub min: Option<f64>,
+    pub avg: Option<f64>,
+    pub max: Option<f64>,
+    /// Hour of day 0-23 in the market's time zone, None where the day has no price
+    pub hours: Vec<Option<f64>>,
+}
+
+/// Summaries of the stored days in the range
+pub fn summaries(from: Date, to: Date, area: &str, currency: &str) -> Vec<DaySummary> {
+    prices(from, to, area)
+        .into_iter()
+        .map(|record| summarize(record.date, &price::slots(&record.prices, currency)))
+        .collect()
+}
+
+fn summarize(date: String, slots: &[structs::Slot]) -> DaySummary {
+    // Weighted by minutes per hour of day, on the 25 hour DST day both 02:00 hours count
+    // towards the same entry
+    let mut sums = [(0.0, 0.0); 24];
+    for slot in slots {
+        let (price_minutes, minutes) = &mut sums[slot.start.hour() as usize];
+        *price_minutes += slot.price * slot.minutes();
+        *minutes += slot.minutes();
+    }
+
+    DaySummary {
+        date,
+        min: slots.iter().map(|s| s.price).reduce(f64::min),
+        avg: price::average_of(slots),
+        max: slots.iter().map(|s| s.price).reduce(f64::max),
+        hours: sums
+            .iter()
+            .map(|&(price_minutes, minutes)| (minutes > 0.0).then(|| price_minutes / minutes))
+            .collect(),
+    }
+}
+
+/// The stored device state changes from `from` to `to`, by the date they happened
+pub fn actions(from: Date, to: Date) -> Vec<ActionRecord> {
+    read(ACTIONS_FILE)
+        .into_iter()
+        .filter(|r: &ActionRecord| {
+            parse_stamp(&r.time).is_some_and(|t| t.date() >= from && t.date() <= to)
+        })
+        .collect()
+}
+
+fn now_string() -> String {
+    OffsetDateTime::now_local()
+        .unwrap_or_else(|_| OffsetDateTime::now_utc())
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+fn append<T: Serialize>(file: &str, record: &T) {
+    let Some(history) = HISTORY.get() else {
+        return;
+    };
+    let Ok(mut last_prune) = history.last_prune.lock() else {
+        return;
+    };
+
+    let today = OffsetDateTime::now_utc().date();
+    if *last_prune != Some(today) {
+        *last_prune = Some(today);
+        history.prune();
+    }
+
+    let result = serde_json::to_string(record)
+        .map_err(anyhow::Error::from)
+        .and_then(|line| {
+            let mut f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(history.dir.join(file))?;
+            writeln!(f, "{}", line)?;
+            Ok(())
+        });
+
+    if let Err(e) = result {
+        warn!("Could not write history {}: {e}", file);
+    }
+}
+
+fn read<T: for<'de> Deserialize<'de>>(file: &str) -> Vec<T> {
+    let Some(history) = HISTORY.get() else {
+        return vec![];
+    };
+    let Ok(f) = File::open(history.dir.join(file)) else {
+        return vec![];
+    };
+
+    BufReader::new(f)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect()
+}
+
+impl History {
+    /// Drop lines older than the retention, 0 keeps everything
+    fn prune(&self) {
+        if self.retention_days == 0 {
+            return;
+        }
+        let cutoff = OffsetDateTime::now_utc() - Duration::days(self.retention_days as i64);
+
+        for (file, key) in [(PRICES_FILE, "date"), (ACTIONS_FILE, "time")] {
+            if let Err(e) = self.prune_file(file, key, cutoff) {
+                warn!("Could not prune history {}: {e}", file);
+            }
+        }
+    }
+
+    /// Keep the lines whose key is not before the cutoff, and those without a time
+    fn prune_file(&self, file: &str, key: &str, cutoff: OffsetDateTime) -> Result<()> {
+        let path = self.dir.join(file);
+        let Ok(f) = File::open(&path) else {
+            return Ok(());
+        };
+
+        let mut kept = String::new();
+        let mut dropped = 0;
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            let value: Value = match serde_json::from_str(&line) {
+                Ok(v) => v,
+                Err(_) => {
+                    dropped += 1;
+                    continue;
+                }
+            };
+            match value.get(key).and_then(Value::as_str).and_then(parse_stamp) {
+                Some(stamp) if stamp < cutoff => dropped += 1,
+                _ => {
+                    kept.push_str(&line);
+                    kept.push('\n');
+                }
+            }
+        }
+
+        if dropped > 0 {
+            let tmp = self.dir.join(format!("{}.tmp", file));
+            fs::write(&tmp, kept)?;
+            fs::rename(tmp, path)?;
+            debug!("Pruned {} old lines from {}", dropped, file);
+        }
+        Ok(())
+    }
+}
+
+/// An RFC3339 time, or an ISO date as its start in UTC
+fn parse_stamp(stamp: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(stamp, &Rfc3339).ok().or_else(|| {
+        Date::parse(stamp, &Iso8601::DATE)
+            .ok()
+            .map(|d| d.midnight().assume_utc())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_compare_as_times_across_offsets() {
+        let local = parse_stamp("2026-10-18T01:00:00+02:00").unwrap();
+        let utc = parse_stamp("2026-10-17T23:30:00Z").unwrap();
+        // As strings the local time sorts after, as times it is earlier
+        assert!(local < utc);
+
+        let date = parse_stamp("2026-10-18").unwrap();
+        assert_eq!(date, parse_stamp("2026-10-18T00:00:00Z").unwrap());
+        assert_eq!(parse_stamp("yesterday"), None);
+    }
+
+    #[test]
+    fn summary_averages_both_hours_of_a_dst_day() {
+        use crate::structs::tests::slots_from;
+        use time::{Month, Time, UtcOffset};
+
+        let (summer, winter) = (
+            UtcOffset::from_hms(2, 0, 0).unwrap(),
+            UtcOffset::from_hms(1, 0, 0).unwrap(),
+        );
+        let midnight = Date::from_calendar_date(2026, Month::October, 25)
+            .unwrap()
+            .with_time(Time::MIDNIGHT)
+            .assume_offset(summer);
+        // Clocks go back at 01:00 UTC, 02:00 comes twice
+        let change = midnight + Duration::hours(3);
+        let mut prices = vec![1.0; 25];
+        prices[2] = 2.0;
+        prices[3] = 4.0;
+        let slots: Vec<structs::Slot> = slots_from(midnight, 60, &prices)
+            .into_iter()
+            .map(|s| {
+                let offset = if s.start >= change { winter } else { summer };
+                structs::Slot {
+                    start: s.start.to_offset(offset),
+                    end: s.end.to_offset(offset),
+                    price: s.price,
+                }
+            })
+            .collect();
+        assert_eq!(slots[3].start.hour(), 2);
+        assert_eq!(slots[24].start.hour(), 23);
+
+        let summary = summarize("2026-10-25".into(), &slots);
+        assert_eq!(summary.hours.len(), 24);
+        assert_eq!(summary.hours[2], Some(3.0));
+        assert_eq!(summary.hours[3], Some(1.0));
+        assert_eq!(summary.hours[23], Some(1.0));
+        assert_eq!(summary.max, Some(4.0));
+    }
+}
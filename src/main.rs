@@ -1,19 +1,32 @@
 use serde_json::Value;
 use std::env;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration as TimeDuration;
+use std::time::{Duration as TimeDuration, Instant};
 
 use anyhow::Result;
 use env_logger::Env;
 use log::{error, info, warn};
 
+mod api;
 mod config;
+mod constraints;
 mod device_model;
+mod events;
 mod functions;
+mod history;
+mod metrics;
+mod notify;
+mod plan;
 mod price;
+mod rules;
+mod smtp;
+mod stats;
 mod structs;
+mod summary;
 mod telldus;
+mod tls;
 mod webui;
 
 /// MAIN
@@ -24,13 +37,16 @@ fn main() -> Result<()> {
     let config_path = config::config_path();
     let config_result = config::read_config_from_file(&config_path);
 
-    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // --dry-run runs the program, the other arguments run a command and exit
+    let dry_run = args.contains(&"--dry-run".into());
+    args.retain(|arg| arg != "--dry-run");
     if !args.is_empty() {
         functions::check_args(&args, &config_result);
     }
 
     // Here we check for a bad config, not sooner; we want the cli args to always work.
-    let config = match config_result {
+    let mut config = match config_result {
         Ok(config) => config,
         Err(structs::ConfigError::Io(e)) => {
             error!("Could not find the config file {:?}: {}", config_path, e);
@@ -40,6 +56,10 @@ fn main() -> Result<()> {
             error!("Errors in the config file. {}", e);
             panic!("{}", e);
         }
+        Err(e @ structs::ConfigError::Invalid(_)) => {
+            error!("Errors in the config file. {}", e);
+            panic!("{}", e);
+        }
     };
 
     let mut devices = match config::read_devices_from_file(&config_path) {
@@ -52,8 +72,26 @@ fn main() -> Result<()> {
 
     info!("Config file: {}", config_path.display());
 
-    let tmp = env::temp_dir();
-    info!("Temp dir: {}", tmp.display());
+    config.dry_run |= dry_run;
+    if config.dry_run {
+        warn!("Dry run, devices are not switched and scripts are not run");
+    }
+    for device in devices
+        .device
+        .iter()
+        .filter(|d| d.dry_run && !config.dry_run)
+    {
+        info!("{}: Dry run", device.name);
+    }
+
+    info!("Cache dir: {}", price::cache_dir(&config).display());
+    price::prune_cache(&config);
+
+    history::init(&config);
+    notify::init(&config);
+
+    // Energy and cost so far, kept across restarts
+    let mut stats = stats::Stats::load(&config);
 
     // Async variables for the web ui
     let asyncdata = Arc::new(Mutex::new(structs::AppState {
@@ -61,34 +99,68 @@ fn main() -> Result<()> {
         devices: (devices.clone()),
         todays_spot_prices: Value::Array(vec![]), // initially empty
         tomorrows_spot_prices: Value::Array(vec![]), // initially empty
+        stats: stats.clone(),
+        fallback: false,
     }));
     let server_data = asyncdata.clone();
     let server_config = config.clone();
     let server_devices = devices.clone();
 
-    functions::get_tomorrow_thread(config.clone());
+    // The tomorrow thread wakes the loop when tomorrow's prices arrive
+    let (replan_tx, replan) = mpsc::channel();
+    functions::get_tomorrow_thread(config.clone(), replan_tx);
 
     // Start webserver in a background thread
-    info!(
-        "Starting the web UI on http://127.0.0.1:{}",
-        config.webui_port
-    );
     thread::spawn(move || {
         webui::run_server(server_data, &server_config, server_devices);
     });
 
+    let mut negative = false;
+    let mut last_tick = Instant::now();
+    let mut last_valid = Instant::now();
+    let mut last_slot = None;
+    let mut last_devices = String::new();
+    let grace = TimeDuration::from_secs(config.failsafe_grace * 60);
+
     // LOOP
     loop {
         // Today
         let today = functions::make_today(&config);
         let todays_spot_prices = match price::read_price_data(today) {
-            Ok(data) => data,
-            Err(err) => {
-                warn!("Failed to read today’s data: {}", err);
-                thread::sleep(TimeDuration::from_secs(config.interval));
+            Ok(data) if price::current_price(&data, &config.currency).is_some() => data,
+            result => {
+                match result {
+                    Err(err) => warn!("Failed to read today’s data: {}", err),
+                    Ok(_) => warn!("No current price in today’s data"),
+                }
+
+                // Without prices for too long, devices fall back to their failsafe
+                if last_valid.elapsed() > grace {
+                    if !asyncdata.lock().unwrap().fallback {
+                        warn!(
+                            "No valid prices for {} minutes, operating on failsafe",
+                            config.failsafe_grace
+                        );
+                    }
+                    // Switching may call Telldus and run scripts, the web UI keeps serving
+                    match functions::failsafe_loop(devices.clone(), &config) {
+                        Ok(updated_devices) => devices = updated_devices,
+                        Err(e) => warn!("{e}"),
+                    }
+                    {
+                        let mut state = asyncdata.lock().unwrap();
+                        state.devices = devices.clone();
+                        state.fallback = true;
+                    }
+                    events::flush();
+                    publish_devices(&devices, &mut last_devices);
+                }
+
+                wait(&replan, TimeDuration::from_secs(config.interval));
                 continue;
             }
         };
+        last_valid = Instant::now();
 
         // Tomorrows prices for the webui async
         let tomorrow = functions::make_tomorrow(&config);
@@ -97,6 +169,20 @@ fn main() -> Result<()> {
             Err(_) => serde_json::json!({}),
         };
 
+        // Count the time since the last round with the states devices had during it
+        stats.record(&devices, &todays_spot_prices, &config, last_tick.elapsed());
+        last_tick = Instant::now();
+        if let Err(e) = stats.save(&config) {
+            warn!("Could not save stats: {e}");
+        }
+
+        let current = price::current_price(&todays_spot_prices, &config.currency);
+        let negative_now = current.is_some_and(|p| price::is_negative(p, &config));
+        if negative_now != negative {
+            functions::negative_event(negative_now, current, &config);
+            negative = negative_now;
+        }
+
         // let updated_devices = functions::logic_loop(&todays_spot_prices, devices, &config)?;
         match functions::logic_loop(
             &todays_spot_prices,
@@ -108,6 +194,14 @@ fn main() -> Result<()> {
             Err(e) => warn!("{e}"),
         }
 
+        // Tell the web UI when a new price slot starts
+        let summary = summary::summary(&todays_spot_prices, &tomorrows_spot_prices, &config, 1);
+        let slot = summary.current.as_ref().map(|c| c.start.clone());
+        if slot != last_slot {
+            events::publish("slot", &summary);
+            last_slot = slot;
+        }
+
         // The async var for the webui
         {
             let mut state = asyncdata.lock().unwrap();
@@ -115,8 +209,60 @@ fn main() -> Result<()> {
             state.devices = devices.clone();
             state.todays_spot_prices = todays_spot_prices.clone(); // JSON Value
             state.tomorrows_spot_prices = tomorrows_spot_prices.clone(); // JSON Value
+            state.stats = stats.clone();
+            if state.fallback {
+                info!("Valid prices again, leaving failsafe");
+                state.fallback = false;
+            }
         }
+        // Device events go out once /devices shows the new states
+        events::flush();
+        publish_devices(&devices, &mut last_devices);
+
+        wait(&replan, TimeDuration::from_secs(config.interval));
+    }
+}
+
+/// Push the whole device list to the web UI when anything in it has changed, e.g. a state,
+/// a trigger price or why a device is suppressed, so the UI doesn't have to poll /devices
+fn publish_devices(devices: &device_model::Devices, last: &mut String) {
+    let Ok(json) = serde_json::to_string(devices) else {
+        return;
+    };
+    if json != *last {
+        events::publish("devices", devices);
+        *last = json;
+    }
+}
+
+/// Sleep for the interval, or until the tomorrow thread asks for a re-plan
+fn wait(replan: &mpsc::Receiver<()>, interval: TimeDuration) {
+    let start = Instant::now();
+    if let Err(mpsc::RecvTimeoutError::Disconnected) = replan.recv_timeout(interval) {
+        // The tomorrow thread has died, sleep out the interval instead of spinning
+        thread::sleep(interval.saturating_sub(start.elapsed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_sleeps_out_the_interval_without_a_tomorrow_thread() {
+        let (tx, replan) = mpsc::channel::<()>();
+        drop(tx);
+        let start = Instant::now();
+        wait(&replan, TimeDuration::from_millis(200));
+        assert!(start.elapsed() >= TimeDuration::from_millis(200));
+    }
 
-        thread::sleep(TimeDuration::from_secs(config.interval));
+    #[test]
+    fn wait_returns_early_on_replan() {
+        let (tx, replan) = mpsc::channel();
+        tx.send(()).unwrap();
+        let start = Instant::now();
+        wait(&replan, TimeDuration::from_secs(10));
+        assert!(start.elapsed() < TimeDuration::from_secs(1));
     }
 }
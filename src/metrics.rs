@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+use time::OffsetDateTime;
+
+use crate::{device_model, price, structs, summary};
+
+/// Counters since startup, the gauges are read from the app state when scraped
+#[derive(Debug, Default)]
+struct Counters {
+    switches: BTreeMap<String, u64>,
+    script_failures: BTreeMap<String, u64>,
+    telldus_failures: BTreeMap<String, u64>,
+    fetch_success: u64,
+    fetch_failure: u64,
+    /// Unix time of the last successful price download
+    last_fetch: Option<i64>,
+}
+
+static COUNTERS: LazyLock<Mutex<Counters>> = LazyLock::new(Default::default);
+
+fn count(update: impl FnOnce(&mut Counters)) {
+    if let Ok(mut counters) = COUNTERS.lock() {
+        update(&mut counters);
+    }
+}
+
+pub fn switched(device: &str) {
+    count(|c| *c.switches.entry(device.to_string()).or_default() += 1);
+}
+
+/// `source` is the device name, or the event that ran the script
+pub fn script_failed(source: &str) {
+    count(|c| *c.script_failures.entry(source.to_string()).or_default() += 1);
+}
+
+pub fn telldus_failed(device: &str) {
+    count(|c| *c.telldus_failures.entry(device.to_string()).or_default() += 1);
+}
+
+pub fn fetch_succeeded() {
+    count(|c| {
+        c.fetch_success += 1;
+        c.last_fetch = Some(OffsetDateTime::now_utc().unix_timestamp());
+    });
+}
+
+pub fn fetch_failed() {
+    count(|c| c.fetch_failure += 1);
+}
+
+/// Prometheus text exposition format
+pub fn render(state: &structs::AppState) -> String {
+    let mut out = String::new();
+    let config = &state.config;
+    let currency = [("currency", config.currency.as_str())];
+
+    let summary = summary::summary(
+        &state.todays_spot_prices,
+        &state.tomorrows_spot_prices,
+        config,
+        1,
+    );
+    if let Some(current) = &summary.current {
+        gauge(
+            &mut out,
+            "rpc_spot_price",
+            "Current spot price",
+            &[(&currency, current.spot)],
+        );
+        gauge(
+            &mut out,
+            "rpc_total_price",
+            "Current price including fees and VAT",
+            &[(&currency, current.total)],
+        );
+    }
+    if let Some(today) = &summary.today {
+        gauge(
+            &mut out,
+            "rpc_day_average_price",
+            "Today's average spot price",
+            &[(&currency, today.avg)],
+        );
+        gauge(
+            &mut out,
+            "rpc_day_min_price",
+            "Today's lowest spot price",
+            &[(&currency, today.min)],
+        );
+        gauge(
+            &mut out,
+            "rpc_day_max_price",
+            "Today's highest spot price",
+            &[(&currency, today.max)],
+        );
+    }
+    gauge(
+        &mut out,
+        "rpc_tomorrow_available",
+        "1 when tomorrow's prices are known",
+        &[(&[], bool_value(summary.tomorrow_available))],
+    );
+    gauge(
+        &mut out,
+        "rpc_fallback",
+        "1 while devices are on their failsafe policy",
+        &[(&[], bool_value(state.fallback))],
+    );
+
+    let devices = &state.devices.device;
+    let labels: Vec<[(&str, &str); 1]> = devices
+        .iter()
+        .map(|d| [("device", d.name.as_str())])
+        .collect();
+    let per_device = |value: fn(&device_model::Device) -> f64| -> Vec<(&[(&str, &str)], f64)> {
+        labels
+            .iter()
+            .zip(devices)
+            .map(|(l, d)| (&l[..], value(d)))
+            .collect()
+    };
+    gauge(
+        &mut out,
+        "rpc_device_on",
+        "1 when the device is On, 0 when Off, -1 when unknown",
+        &per_device(|d| match d.state {
+            device_model::State::On => 1.0,
+            device_model::State::Off => 0.0,
+            _ => -1.0,
+        }),
+    );
+    gauge(
+        &mut out,
+        "rpc_device_trigger_price",
+        "Spot price below which the device is On today",
+        &per_device(|d| d.today_trigger_price),
+    );
+
+    let Ok(counters) = COUNTERS.lock() else {
+        return out;
+    };
+    counter_map(
+        &mut out,
+        "rpc_device_switches_total",
+        "State changes per device",
+        "device",
+        &counters.switches,
+    );
+    counter_map(
+        &mut out,
+        "rpc_script_failures_total",
+        "Scripts that could not run or exited with an error",
+        "source",
+        &counters.script_failures,
+    );
+    counter_map(
+        &mut out,
+        "rpc_telldus_failures_total",
+        "Failed Telldus requests per device",
+        "device",
+        &counters.telldus_failures,
+    );
+    counter(
+        &mut out,
+        "rpc_price_fetch_success_total",
+        "Successful price downloads",
+        counters.fetch_success,
+    );
+    counter(
+        &mut out,
+        "rpc_price_fetch_failure_total",
+        "Failed price downloads",
+        counters.fetch_failure,
+    );
+    if let Some(last) = counters.last_fetch {
+        gauge(
+            &mut out,
+            "rpc_price_last_fetch_timestamp_seconds",
+            "Unix time of the last successful price download",
+            &[(&[], last as f64)],
+        );
+    }
+    // Days still in backoff, a scrape between retries shows the outage
+    gauge(
+        &mut out,
+        "rpc_price_fetch_failing_days",
+        "Days whose prices are failing to download",
+        &[(&[], price::fetch_status().failing.len() as f64)],
+    );
+
+    out
+}
+
+fn bool_value(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, samples: &[(&[(&str, &str)], f64)]) {
+    header(out, name, help, "gauge");
+    for (labels, value) in samples {
+        sample(out, name, labels, *value);
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    sample(out, name, &[], value as f64);
+}
+
+fn counter_map(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: &BTreeMap<String, u64>,
+) {
+    header(out, name, help, "counter");
+    for (key, value) in values {
+        sample(out, name, &[(label, key)], *value as f64);
+    }
+}
+
+/// Label values escape backslash, double quote and newline
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape("two\nlines"), "two\\nlines");
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn gauges_have_help_type_and_labelled_samples() {
+        let mut out = String::new();
+        gauge(
+            &mut out,
+            "rpc_test",
+            "A test gauge",
+            &[
+                (&[("device", "Heater \"1\"")], 1.5),
+                (&[("device", "Pump"), ("zone", "b")], 0.0),
+            ],
+        );
+        assert_eq!(
+            out,
+            "# HELP rpc_test A test gauge\n\
+             # TYPE rpc_test gauge\n\
+             rpc_test{device=\"Heater \\\"1\\\"\"} 1.5\n\
+             rpc_test{device=\"Pump\",zone=\"b\"} 0\n"
+        );
+    }
+
+    #[test]
+    fn counters_without_labels_are_bare_samples() {
+        let mut out = String::new();
+        counter(&mut out, "rpc_total", "A test counter", 3);
+        assert_eq!(
+            out,
+            "# HELP rpc_total A test counter\n# TYPE rpc_total counter\nrpc_total 3\n"
+        );
+
+        let mut out = String::new();
+        let values = BTreeMap::from([("b".to_string(), 2), ("a".to_string(), 1)]);
+        counter_map(&mut out, "rpc_map", "Per key", "key", &values);
+        assert!(out.ends_with("rpc_map{key=\"a\"} 1\nrpc_map{key=\"b\"} 2\n"));
+    }
+}
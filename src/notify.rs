@@ -0,0 +1,364 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration as TimeDuration;
+
+use crate::{smtp, structs};
+
+/// Something worth telling the user about
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TomorrowPrices {
+        date: String,
+        average: Option<f64>,
+        min: Option<f64>,
+        max: Option<f64>,
+        currency: String,
+    },
+    PriceAbove {
+        price: f64,
+        threshold: f64,
+        currency: String,
+    },
+    PriceBelow {
+        price: f64,
+        threshold: f64,
+        currency: String,
+    },
+    /// Telldus did not accept a switch
+    SwitchFailed {
+        device: String,
+        action: String,
+        error: String,
+    },
+    /// A device, tomorrow or negative price script could not run or exited with an error
+    ScriptFailed { source: String, error: String },
+    FetchFailing {
+        date: String,
+        hours: u64,
+        error: String,
+    },
+    /// Start and end of a negative price period
+    NegativePrice {
+        active: bool,
+        price: Option<f64>,
+        currency: String,
+    },
+}
+
+/// Every event kind, for checking the config
+pub const KINDS: &[&str] = &[
+    "tomorrow_prices",
+    "price_above",
+    "price_below",
+    "switch_failed",
+    "script_failed",
+    "fetch_failing",
+    "negative_price",
+];
+
+impl Event {
+    /// The name used in a notifier's `events` list
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::TomorrowPrices { .. } => "tomorrow_prices",
+            Event::PriceAbove { .. } => "price_above",
+            Event::PriceBelow { .. } => "price_below",
+            Event::SwitchFailed { .. } => "switch_failed",
+            Event::ScriptFailed { .. } => "script_failed",
+            Event::FetchFailing { .. } => "fetch_failing",
+            Event::NegativePrice { .. } => "negative_price",
+        }
+    }
+
+    pub fn title(&self) -> String {
+        match self {
+            Event::TomorrowPrices { date, .. } => format!("Prices for {} published", date),
+            Event::PriceAbove { .. } => "Electricity price is high".to_string(),
+            Event::PriceBelow { .. } => "Electricity price is low".to_string(),
+            Event::SwitchFailed { device, .. } => format!("{} failed to switch", device),
+            Event::ScriptFailed { source, .. } => format!("{} script failed", source),
+            Event::FetchFailing { .. } => "Price download failing".to_string(),
+            Event::NegativePrice { active: true, .. } => "Negative electricity price".to_string(),
+            Event::NegativePrice { active: false, .. } => "Negative price period ended".to_string(),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        let price = |p: Option<f64>| p.map_or("unknown".to_string(), |p| format!("{:.2}", p));
+        match self {
+            Event::TomorrowPrices {
+                average,
+                min,
+                max,
+                currency,
+                ..
+            } => format!(
+                "Average {}, min {}, max {} {}",
+                price(*average),
+                price(*min),
+                price(*max),
+                currency
+            ),
+            Event::PriceAbove {
+                price,
+                threshold,
+                currency,
+            } => format!(
+                "Spot price {:.2} {} is above {:.2}",
+                price, currency, threshold
+            ),
+            Event::PriceBelow {
+                price,
+                threshold,
+                currency,
+            } => format!(
+                "Spot price {:.2} {} is below {:.2}",
+                price, currency, threshold
+            ),
+            Event::SwitchFailed { action, error, .. } => {
+                format!("Switching {} failed: {}", action, error)
+            }
+            Event::ScriptFailed { error, .. } => error.clone(),
+            Event::FetchFailing {
+                date, hours, error, ..
+            } => format!(
+                "Prices for {} have failed to download for {} h: {}",
+                date, hours, error
+            ),
+            Event::NegativePrice {
+                price: p, currency, ..
+            } => format!("Spot price {} {}", price(*p), currency),
+        }
+    }
+}
+
+/// One configured destination, `[[notifier]]` in the config file
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Notifier {
+    /// Event kinds to send, empty sends all of them
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(flatten)]
+    pub sink: Sink,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Sink {
+    Smtp(Smtp),
+    /// An ntfy topic url, or any server that takes the message as the POST body
+    Ntfy {
+        url: String,
+        /// Access token, sent as a Bearer token
+        #[serde(default, skip_serializing)]
+        token: String,
+    },
+    /// POST the event as JSON
+    Webhook {
+        url: String,
+    },
+    /// Run with RPC_EVENT, RPC_TITLE, RPC_MESSAGE and RPC_JSON set
+    Command {
+        command: String,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Smtp {
+    pub server: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: Security,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default, skip_serializing)]
+    pub password: String,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    #[default]
+    Starttls,
+    /// Implicit TLS, usually port 465
+    Tls,
+    None,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+struct Notify {
+    notifiers: Vec<Notifier>,
+    price_above: Option<f64>,
+    price_below: Option<f64>,
+    currency: String,
+    fetch_hours: u64,
+    /// Webhooks and commands may switch things, so dry run only logs them
+    dry_run: bool,
+    /// Whether the price was above and below the thresholds last round
+    crossed: Mutex<(bool, bool)>,
+}
+
+static NOTIFY: OnceLock<Notify> = OnceLock::new();
+
+/// Set up the notifiers, called once at startup
+pub fn init(config: &structs::Config) {
+    let mut notifiers = config.notifier.clone();
+    // tomorrow_webhook always sent the same JSON as a webhook notifier
+    if !config.tomorrow_webhook.is_empty() {
+        notifiers.push(Notifier {
+            events: vec!["tomorrow_prices".to_string()],
+            sink: Sink::Webhook {
+                url: config.tomorrow_webhook.clone(),
+            },
+        });
+    }
+    if notifiers.is_empty() {
+        return;
+    }
+    info!("Notifications: {} notifier(s)", notifiers.len());
+
+    let _ = NOTIFY.set(Notify {
+        notifiers,
+        price_above: config.notify_price_above,
+        price_below: config.notify_price_below,
+        currency: config.currency.clone(),
+        fetch_hours: config.notify_fetch_hours,
+        dry_run: config.dry_run,
+        crossed: Mutex::new((false, false)),
+    });
+}
+
+/// Send an event to every notifier that wants it, in the background
+pub fn send(event: Event) {
+    let Some(notify) = NOTIFY.get() else {
+        return;
+    };
+    let kind = event.kind();
+
+    for notifier in &notify.notifiers {
+        if !notifier.events.is_empty() && !notifier.events.iter().any(|e| e == kind) {
+            continue;
+        }
+        match &notifier.sink {
+            Sink::Webhook { url } if notify.dry_run => {
+                info!("Dry run, would POST {} to {}", kind, url);
+                continue;
+            }
+            Sink::Command { command } if notify.dry_run => {
+                info!("Dry run, would execute {:?} for {}", command, kind);
+                continue;
+            }
+            _ => {}
+        }
+        let sink = notifier.sink.clone();
+        let event = event.clone();
+        thread::spawn(move || {
+            if let Err(e) = deliver(&sink, &event) {
+                warn!("Notification {} failed: {e}", kind);
+            }
+        });
+    }
+}
+
+/// Notify once when the spot price crosses above or below the configured thresholds
+pub fn check_price(price: f64) {
+    let Some(notify) = NOTIFY.get() else {
+        return;
+    };
+    let Ok(mut crossed) = notify.crossed.lock() else {
+        return;
+    };
+    let (was_above, was_below) = *crossed;
+
+    let above = notify.price_above.filter(|t| price > *t);
+    if let (Some(threshold), false) = (above, was_above) {
+        send(Event::PriceAbove {
+            price,
+            threshold,
+            currency: notify.currency.clone(),
+        });
+    }
+    let below = notify.price_below.filter(|t| price < *t);
+    if let (Some(threshold), false) = (below, was_below) {
+        send(Event::PriceBelow {
+            price,
+            threshold,
+            currency: notify.currency.clone(),
+        });
+    }
+
+    *crossed = (above.is_some(), below.is_some());
+}
+
+/// Hours of failed downloads before fetch_failing is sent, None without notifiers
+pub fn fetch_hours() -> Option<u64> {
+    NOTIFY.get().map(|n| n.fetch_hours)
+}
+
+fn deliver(sink: &Sink, event: &Event) -> Result<()> {
+    let title = event.title();
+    let message = event.message();
+
+    match sink {
+        Sink::Smtp(config) => smtp::send(config, &title, &message),
+        Sink::Ntfy { url, token } => {
+            let client = reqwest::blocking::Client::builder()
+                .timeout(TimeDuration::from_secs(10))
+                .build()?;
+            let mut request = client
+                .post(url)
+                .header("Title", &title)
+                .header("Tags", event.kind())
+                .body(message);
+            if !token.is_empty() {
+                request = request.bearer_auth(token);
+            }
+            request.send()?.error_for_status()?;
+            Ok(())
+        }
+        Sink::Webhook { url } => {
+            let client = reqwest::blocking::Client::builder()
+                .timeout(TimeDuration::from_secs(10))
+                .build()?;
+            client.post(url).json(event).send()?.error_for_status()?;
+            Ok(())
+        }
+        Sink::Command { command } => {
+            #[cfg(unix)]
+            let mut cmd = {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(command);
+                cmd
+            };
+
+            #[cfg(windows)]
+            let mut cmd = {
+                let mut cmd = Command::new("cmd");
+                cmd.arg("/C").arg(command);
+                cmd
+            };
+
+            let status = cmd
+                .env("RPC_EVENT", event.kind())
+                .env("RPC_TITLE", &title)
+                .env("RPC_MESSAGE", &message)
+                .env("RPC_JSON", serde_json::to_string(event)?)
+                .status()?;
+            if !status.success() {
+                return Err(anyhow!("{} exited with {}", command, status));
+            }
+            Ok(())
+        }
+    }
+}
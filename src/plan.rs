@@ -0,0 +1,244 @@
+use serde::Serialize;
+use serde_json::Value;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{device_model, functions, price, structs};
+
+/// The predicted states of every device over today and tomorrow
+#[derive(Serialize, Debug, Clone)]
+pub struct Plan {
+    pub currency: String,
+    pub devices: Vec<DevicePlan>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DevicePlan {
+    pub name: String,
+    /// Consecutive slots with the same state merged
+    pub spans: Vec<Span>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Span {
+    pub start: String,
+    pub end: String,
+    pub state: device_model::State,
+    /// Why the device got this state at the start of the span
+    pub reason: String,
+    /// Average spot price over the span, weighted by slot length
+    pub spot: f64,
+    #[serde(skip)]
+    from: OffsetDateTime,
+    #[serde(skip)]
+    to: OffsetDateTime,
+    #[serde(skip)]
+    minutes: f64,
+}
+
+/// Run the control round at the start of every slot of today and tomorrow, on copies of the
+/// devices, without switching anything. Tomorrow is planned as if it were today, without the
+/// day after it.
+pub fn plan(
+    today: &Value,
+    tomorrow: &Value,
+    devices: &device_model::Devices,
+    config: &structs::Config,
+) -> Plan {
+    let mut devices = devices.clone();
+    // The simulation starts at midnight, before the real switches and shed minutes of today
+    for device in devices.device.iter_mut() {
+        device.switched_at = None;
+        device.shed_minutes = 0.0;
+        device.shed_day = None;
+        device.shed_at = None;
+    }
+    let mut plans: Vec<DevicePlan> = devices
+        .device
+        .iter()
+        .map(|d| DevicePlan {
+            name: d.name.clone(),
+            spans: vec![],
+        })
+        .collect();
+    let none = Value::Array(vec![]);
+
+    for (day, next) in [(today, tomorrow), (tomorrow, &none)] {
+        for slot in price::slots(day, &config.currency) {
+            let decisions = functions::decide(day, next, &mut devices, config, slot.start);
+
+            for ((device, decision), plan) in
+                devices.device.iter_mut().zip(decisions).zip(&mut plans)
+            {
+                let reason = match decision {
+                    Some((state, reason)) => {
+                        if device.state != state {
+                            device.state = state;
+                            device.switched_at = Some(slot.start);
+                        }
+                        reason
+                    }
+                    None => plan
+                        .spans
+                        .last()
+                        .map_or("Current state".to_string(), |s| s.reason.clone()),
+                };
+                extend(&mut plan.spans, &slot, &device.state, reason);
+            }
+        }
+    }
+
+    Plan {
+        currency: config.currency.clone(),
+        devices: plans,
+    }
+}
+
+/// Add a slot to the last span if it continues it in the same state
+fn extend(
+    spans: &mut Vec<Span>,
+    slot: &structs::Slot,
+    state: &device_model::State,
+    reason: String,
+) {
+    if let Some(last) = spans.last_mut() {
+        if last.state == *state && last.to == slot.start {
+            let minutes = last.minutes + slot.minutes();
+            last.spot = (last.spot * last.minutes + slot.price * slot.minutes()) / minutes;
+            last.minutes = minutes;
+            last.to = slot.end;
+            last.end = format(slot.end);
+            return;
+        }
+    }
+    spans.push(Span {
+        start: format(slot.start),
+        end: format(slot.end),
+        state: state.clone(),
+        reason,
+        spot: slot.price,
+        from: slot.start,
+        to: slot.end,
+        minutes: slot.minutes(),
+    });
+}
+
+/// The plan as a text table, for the command line
+pub fn table(plan: &Plan) -> String {
+    let width = plan
+        .devices
+        .iter()
+        .map(|d| d.name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("Device".len());
+
+    let mut out = format!(
+        "{:<width$}  {:<16}  {:<16}  {:<7}  {:>8}  Reason\n",
+        "Device", "Start", "End", "State", "Spot"
+    );
+    for device in &plan.devices {
+        for span in &device.spans {
+            out.push_str(&format!(
+                "{:<width$}  {:<16}  {:<16}  {:<7}  {:>8.2}  {}\n",
+                device.name,
+                short(span.from),
+                short(span.to),
+                format!("{:?}", span.state),
+                span.spot,
+                span.reason
+            ));
+        }
+    }
+    out
+}
+
+/// YYYY-MM-DD HH:MM
+fn short(time: OffsetDateTime) -> String {
+    format!("{} {:02}:{:02}", time.date(), time.hour(), time.minute())
+}
+
+fn format(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_model::State;
+    use crate::structs::tests::{config, json_of, midnight, slots, slots_from};
+    use time::Duration;
+
+    fn devices(toml: &str) -> device_model::Devices {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// (state, start hour, end hour) of each span, hours counted from today's midnight
+    fn spans(plan: &DevicePlan) -> Vec<(State, i64, i64)> {
+        plan.spans
+            .iter()
+            .map(|s| {
+                (
+                    s.state.clone(),
+                    (s.from - midnight()).whole_hours(),
+                    (s.to - midnight()).whole_hours(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spans_merge_and_run_on_into_tomorrow() {
+        let devices = devices(
+            r#"
+            [[device]]
+            name = "heater"
+            mode = "Price"
+            price = 1.5
+            "#,
+        );
+        let mut today = vec![1.0; 24];
+        today[5] = 2.0;
+        let tomorrow = slots_from(midnight() + Duration::days(1), 60, &[1.0; 24]);
+
+        let plan = plan(
+            &json_of(&slots(60, &today)),
+            &json_of(&tomorrow),
+            &devices,
+            &config(),
+        );
+
+        let heater = &plan.devices[0];
+        assert_eq!(heater.name, "heater");
+        assert_eq!(
+            spans(heater),
+            vec![(State::On, 0, 5), (State::Off, 5, 6), (State::On, 6, 48)]
+        );
+        assert_eq!(heater.spans[1].spot, 2.0);
+        assert_eq!(heater.spans[2].spot, 1.0);
+        // Planning doesn't touch the real devices
+        assert_eq!(devices.device[0].state, State::Unknown);
+    }
+
+    #[test]
+    fn min_run_holds_the_device_on() {
+        let devices = devices(
+            r#"
+            [[device]]
+            name = "pump"
+            mode = "Price"
+            price = 1.5
+            min_run = 120
+            "#,
+        );
+        let today = slots(60, &[1.0, 2.0, 2.0, 2.0, 1.0]);
+
+        let plan = plan(&json_of(&today), &Value::Array(vec![]), &devices, &config());
+
+        assert_eq!(
+            spans(&plan.devices[0]),
+            vec![(State::On, 0, 2), (State::Off, 2, 4), (State::On, 4, 5)]
+        );
+        // Weighted over both hours of the merged span
+        assert_eq!(plan.devices[0].spans[0].spot, 1.5);
+    }
+}
@@ -1,129 +1,578 @@
 use anyhow::{anyhow, Result};
-use log::{debug, info};
+use log::{debug, info, warn};
 use reqwest::blocking::Client;
-use serde_json::Value;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::time::Duration as TimeDuration;
-use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration as TimeDuration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+use time::format_description::well_known::{Iso8601, Rfc2822, Rfc3339};
+use time::{Date, Duration, OffsetDateTime, Time};
 
-use crate::structs;
+use crate::{events, history, metrics, notify, structs};
+
+const BACKOFF_BASE: TimeDuration = TimeDuration::from_secs(10);
+const BACKOFF_MAX: TimeDuration = TimeDuration::from_secs(3600);
+
+/// Price download status per day, shown in /health
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct FetchStatus {
+    pub last_success: Option<String>,
+    pub last_error: Option<String>,
+    /// Days that are currently failing, keyed by YYYY-MM-DD
+    pub failing: BTreeMap<String, DayFetch>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DayFetch {
+    pub failures: u32,
+    pub last_error: String,
+    pub next_retry: String,
+    #[serde(skip)]
+    retry_at: Instant,
+    /// First failure since the last success
+    #[serde(skip)]
+    since: Instant,
+    /// fetch_failing has been sent for this outage
+    #[serde(skip)]
+    notified: bool,
+}
+
+static FETCH: LazyLock<Mutex<FetchStatus>> = LazyLock::new(Default::default);
+
+/// A copy of the current download status
+pub fn fetch_status() -> FetchStatus {
+    FETCH.lock().map(|s| s.clone()).unwrap_or_default()
+}
+
+/// A failed download, with the server's Retry-After if it sent one
+struct DownloadError {
+    error: anyhow::Error,
+    retry_after: Option<TimeDuration>,
+}
+
+impl<E: Into<anyhow::Error>> From<E> for DownloadError {
+    fn from(e: E) -> Self {
+        DownloadError {
+            error: e.into(),
+            retry_after: None,
+        }
+    }
+}
 
 /// Download json from url
-fn download_prices(url: &str) -> Result<Value> {
+fn download_prices(url: &str) -> Result<Value, DownloadError> {
     let client = Client::builder()
         .timeout(TimeDuration::from_secs(10))
         .build()?;
     let response = client.get(url).send()?;
 
     if !response.status().is_success() {
-        return Err(anyhow!("HTTP error: {}", response.status()));
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        return Err(DownloadError {
+            error: anyhow!("HTTP error: {}", response.status()),
+            retry_after,
+        });
     }
 
     let json = response.json::<Value>()?;
 
     if !json.is_array() {
-        return Err(anyhow!("Expected array at root of JSON, got: {}", json));
+        return Err(anyhow!("Expected array at root of JSON, got: {}", json).into());
     }
 
     Ok(json)
 }
 
-/// Get local json data
-fn load_prices_from_file(file: String) -> Result<Value> {
-    let mut path = env::temp_dir();
-    path.push(file);
-    let json = std::fs::read_to_string(path)?;
-    let value: Value = serde_json::from_str(&json)?;
+/// Retry-After is either seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<TimeDuration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(TimeDuration::from_secs(secs));
+    }
+    let at = OffsetDateTime::parse(value.trim(), &Rfc2822).ok()?;
+    let wait = at - OffsetDateTime::now_utc();
+    wait.try_into().ok()
+}
+
+/// Time left before the day may be downloaded again
+fn backoff_remaining(day: &structs::Day) -> Option<TimeDuration> {
+    let status = FETCH.lock().ok()?;
+    let fetch = status.failing.get(&day.date.to_string())?;
+    fetch.retry_at.checked_duration_since(Instant::now())
+}
 
-    if !value.is_array() {
-        return Err(anyhow!("Expected array at root of JSON, got: {}", value));
+fn record_success(day: &structs::Day) {
+    events::publish(
+        "fetch",
+        &json!({ "date": day.date.to_string(), "ok": true }),
+    );
+    metrics::fetch_succeeded();
+    if let Ok(mut status) = FETCH.lock() {
+        status.failing.remove(&day.date.to_string());
+        status.last_success = OffsetDateTime::now_local()
+            .unwrap_or_else(|_| OffsetDateTime::now_utc())
+            .format(&Rfc3339)
+            .ok();
     }
+}
 
-    Ok(value)
+/// Exponential backoff with jitter up to `max`, or the server's Retry-After if that is longer
+fn record_failure(
+    day: &structs::Day,
+    error: &str,
+    retry_after: Option<TimeDuration>,
+    max: TimeDuration,
+) {
+    events::publish(
+        "fetch",
+        &json!({ "date": day.date.to_string(), "ok": false, "error": error }),
+    );
+    metrics::fetch_failed();
+    let Ok(mut status) = FETCH.lock() else {
+        return;
+    };
+    status.last_error = Some(format!("{}: {}", day.date, error));
+
+    let previous = status.failing.get(&day.date.to_string());
+    let failures = previous.map_or(1, |f| f.failures + 1);
+    let since = previous.map_or_else(Instant::now, |f| f.since);
+    let mut notified = previous.is_some_and(|f| f.notified);
+
+    if let Some(hours) = notify::fetch_hours() {
+        if !notified && since.elapsed() >= TimeDuration::from_secs(hours * 3600) {
+            notify::send(notify::Event::FetchFailing {
+                date: day.date.to_string(),
+                hours,
+                error: error.to_string(),
+            });
+            notified = true;
+        }
+    }
+
+    let backoff = BACKOFF_BASE.saturating_mul(2u32.saturating_pow(failures - 1));
+    // +-20% so several instances don't retry in step
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let jitter = 0.8 + 0.4 * (nanos % 1000) as f64 / 1000.0;
+    let wait = backoff
+        .mul_f64(jitter)
+        .min(max)
+        .max(retry_after.unwrap_or_default());
+
+    let next_retry = (OffsetDateTime::now_utc() + wait)
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    debug!("Retrying {} in {} s", day.date, wait.as_secs());
+
+    status.failing.insert(
+        day.date.to_string(),
+        DayFetch {
+            failures,
+            last_error: error.to_string(),
+            next_retry,
+            retry_at: Instant::now() + wait,
+            since,
+            notified,
+        },
+    );
+}
+
+/// The directory for cached price files, a `pricecontrol` directory in cache_dir, the system
+/// cache dir or the temp dir. Pruning and --clear-cache never touch anything else in those.
+pub fn cache_dir(config: &structs::Config) -> PathBuf {
+    let mut dir = if !config.cache_dir.is_empty() {
+        PathBuf::from(&config.cache_dir)
+    } else {
+        dirs::cache_dir().unwrap_or_else(env::temp_dir)
+    };
+    dir.push("pricecontrol");
+    dir
+}
+
+/// A file name safe key for the price provider, from the api host
+pub fn provider_key(api: &str) -> String {
+    let host = api
+        .split("://")
+        .last()
+        .unwrap_or(api)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+
+    host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
 }
 
-/// Save json data to local file
-fn save_prices_to_file(value: &Value, file: &str) -> Result<()> {
-    let mut path = env::temp_dir();
-    path.push(file);
+/// Remove cached price files older than the given number of days
+pub fn prune_cache(config: &structs::Config) {
+    let max_age = TimeDuration::from_secs(config.cache_days.saturating_mul(24 * 3600));
+    let Ok(entries) = fs::read_dir(cache_dir(config)) else {
+        return;
+    };
 
-    let mut tmp_path = env::temp_dir();
-    tmp_path.push(file);
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !is_cache_file(&path) {
+            continue;
+        }
+        let old = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .is_ok_and(|t| t.elapsed().unwrap_or_default() > max_age);
 
-    let mut file = File::create(&tmp_path)?;
+        if old {
+            match fs::remove_file(&path) {
+                Ok(_) => debug!("Removed old cache file {}", path.display()),
+                Err(e) => warn!("Could not remove cache file {}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+/// Cached price files, `{provider}_{YYYY-MM-DD}_{area}_{currency}.json`, and the same with
+/// `.bad` added when quarantined
+fn is_cache_file(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    let name = name.strip_suffix(".bad").unwrap_or(name);
+    let Some(name) = name.strip_suffix(".json") else {
+        return false;
+    };
+    let Some((provider, rest)) = name.split_once('_') else {
+        return false;
+    };
+    let Some((date, area_currency)) = rest.split_once('_') else {
+        return false;
+    };
+
+    !provider.is_empty()
+        && provider
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && Date::parse(date, &Iso8601::DATE).is_ok()
+        && date.len() == 10
+        && area_currency.contains('_')
+}
+
+/// Remove all cached price files, returns how many were removed
+pub fn clear_cache(config: &structs::Config) -> Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(cache_dir(config))?.flatten() {
+        let path = entry.path();
+        if is_cache_file(&path) {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Check that price data is complete for the day: every entry has a price and
+/// times, the slots follow each other without gaps, and they cover the whole day
+fn validate_prices(value: &Value, day: &structs::Day) -> Result<()> {
+    let array = value
+        .as_array()
+        .ok_or_else(|| anyhow!("Expected array at root of JSON"))?;
+    let slots = slots(value, &day.currency);
+
+    if slots.is_empty() || slots.len() != array.len() {
+        return Err(anyhow!(
+            "{} of {} entries have times and a {} price",
+            slots.len(),
+            array.len(),
+            day.currency
+        ));
+    }
+
+    if let Some(gap) = slots.windows(2).find(|w| w[0].end != w[1].start) {
+        return Err(anyhow!("Gap in prices after {}", gap[0].end));
+    }
+
+    let first = &slots[0].start;
+    let last = &slots[slots.len() - 1].end;
+    if first.date() != day.date
+        || first.time() != Time::MIDNIGHT
+        || Some(last.date()) != day.date.next_day()
+        || last.time() != Time::MIDNIGHT
+    {
+        return Err(anyhow!(
+            "Prices cover {} to {}, not all of {}",
+            first,
+            last,
+            day.date
+        ));
+    }
+
+    Ok(())
+}
+
+/// Get local json data
+fn load_prices_from_file(day: &structs::Day) -> Result<Value> {
+    let json = std::fs::read_to_string(&day.file)?;
+    let value: Value = serde_json::from_str(&json)?;
+    validate_prices(&value, day)?;
+
+    Ok(value)
+}
+
+/// Save json data to local file atomically: write and sync a temp file in the same
+/// directory, then rename it over the target, so a crash never leaves a truncated file
+fn save_prices_to_file(value: &Value, path: &Path) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("No directory in {}", path.display()))?;
+    fs::create_dir_all(dir)?;
+
+    let mut tmp = NamedTempFile::new_in(dir)?;
     let pretty = serde_json::to_string_pretty(value)?;
-    file.write_all(pretty.as_bytes())?;
+    tmp.write_all(pretty.as_bytes())?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path)?;
+
+    // Make the rename itself durable
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
 
-    std::fs::rename(tmp_path, path)?;
     Ok(())
 }
 
+/// Move an invalid cached file aside so it is downloaded again, and kept for inspection
+fn quarantine(path: &Path, err: &anyhow::Error) {
+    let mut bad = path.as_os_str().to_owned();
+    bad.push(".bad");
+
+    match fs::rename(path, &bad) {
+        Ok(_) => warn!(
+            "Invalid cached prices {}: {err}. Moved to {}",
+            path.display(),
+            Path::new(&bad).display()
+        ),
+        Err(e) => warn!("Could not quarantine {}: {e}", path.display()),
+    }
+}
+
 pub fn read_price_data(day: structs::Day) -> Result<Value> {
     match try_load_local(&day) {
         Ok(data) => Ok(data),
-        Err(_) => try_download_and_save(&day),
+        Err(_) => try_download_and_save(&day, BACKOFF_MAX),
+    }
+}
+
+/// Like read_price_data, for a caller that polls on its own schedule: the backoff never
+/// waits longer than `poll`, so every scheduled poll gets to try the download
+pub fn poll_price_data(day: structs::Day, poll: TimeDuration) -> Result<Value> {
+    match try_load_local(&day) {
+        Ok(data) => Ok(data),
+        Err(_) => try_download_and_save(&day, poll.min(BACKOFF_MAX)),
     }
 }
 
 pub fn try_load_local(day: &structs::Day) -> Result<Value> {
-    match load_prices_from_file(day.file.clone()) {
+    match load_prices_from_file(day) {
         Ok(data) => {
-            debug!("Reading local file: {}", day.file);
+            debug!("Reading local file: {}", day.file.display());
             Ok(data)
         }
-        Err(err) => Err(err),
+        Err(err) => {
+            if day.file.exists() {
+                quarantine(&day.file, &err);
+            }
+            Err(err)
+        }
     }
 }
 
-fn try_download_and_save(day: &structs::Day) -> Result<Value> {
-    debug!("Attempting download {}", day.url);
-    let data = download_prices(&day.url)?;
-    let _ = save_prices_to_file(&data, &day.file);
-    info!("Prices for {} downloaded", day.date);
-    Ok(data)
+/// Try the api and then each fallback in order, backing off while all of them fail
+fn try_download_and_save(day: &structs::Day, max_backoff: TimeDuration) -> Result<Value> {
+    if let Some(wait) = backoff_remaining(day) {
+        return Err(anyhow!(
+            "Waiting {} s before retrying the download",
+            wait.as_secs()
+        ));
+    }
+
+    let mut errors = vec![];
+    let mut retry_after = None;
+
+    for (i, url) in day.urls.iter().enumerate() {
+        debug!("Attempting download {}", url);
+        let result = download_prices(url).and_then(|data| {
+            validate_prices(&data, day)
+                .map(|_| data)
+                .map_err(Into::into)
+        });
+
+        match result {
+            Ok(data) => {
+                let _ = save_prices_to_file(&data, &day.file);
+                history::record_prices(day, &data);
+                record_success(day);
+                if i == 0 {
+                    info!("Prices for {} downloaded", day.date);
+                } else {
+                    info!("Prices for {} downloaded from fallback {}", day.date, url);
+                }
+                return Ok(data);
+            }
+            Err(e) => {
+                retry_after = retry_after.max(e.retry_after);
+                errors.push(format!("{}: {}", url, e.error));
+            }
+        }
+    }
+
+    let error = errors.join("; ");
+    record_failure(day, &error, retry_after, max_backoff);
+    Err(anyhow!(error))
 }
 
 /// Return the current price
 pub fn current_price(json: &Value, currency: &str) -> Option<f64> {
+    price_at(json, currency, OffsetDateTime::now_local().ok()?)
+}
+
+/// Return the price at the given time
+pub fn price_at(json: &Value, currency: &str, at: OffsetDateTime) -> Option<f64> {
     json.as_array()?
         .iter()
-        .find_map(|obj| extract_valid_price(obj, currency))
+        .find_map(|obj| extract_valid_price(obj, currency, at))
+}
+
+/// Return the price of the slot covering the given time
+pub fn slot_price_at(slots: &[structs::Slot], at: OffsetDateTime) -> Option<f64> {
+    slots
+        .iter()
+        .find(|slot| at >= slot.start && at < slot.end)
+        .map(|slot| slot.price)
 }
 
-/// Return the average price
+/// Return the average price, weighted by slot length
 pub fn average_price(json: &Value, currency: &str) -> Option<f64> {
-    let array = json.as_array()?;
+    average_of(&slots(json, currency))
+}
 
-    let prices: Vec<f64> = array
-        .iter()
-        .filter_map(|obj| obj.get(currency)?.as_f64())
-        .collect();
+/// Return the average price of a list of slots, weighted by slot length
+pub fn average_of(slots: &[structs::Slot]) -> Option<f64> {
+    let minutes: f64 = slots.iter().map(structs::Slot::minutes).sum();
 
-    if prices.is_empty() {
+    if minutes <= 0.0 {
         None
     } else {
-        Some(prices.iter().sum::<f64>() / prices.len() as f64)
+        Some(slots.iter().map(|s| s.price * s.minutes()).sum::<f64>() / minutes)
     }
 }
 
-/// Return the n'th sorted price for Ratio mode
-pub fn ratio_price(json: &Value, currency: &str, ratio: f64) -> Option<f64> {
-    let array = json.as_array()?;
-    let safe_ratio = ratio.clamp(0.0, 1.0);
-    let index = ((array.len() - 1) as f64 * safe_ratio).floor() as usize;
+/// Return the trigger price for Ratio mode: the price of the first slot after the cheapest ones
+/// that cover at least the ratio of the total duration, so the device runs the ratio rounded up
+/// to whole slots. Counting minutes instead of entries keeps days with mixed resolution correct.
+pub fn ratio_of(slots: &[structs::Slot], ratio: f64) -> Option<f64> {
+    let mut sorted: Vec<&structs::Slot> = slots.iter().collect();
+    sorted.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
+
+    let total: f64 = sorted.iter().map(|s| s.minutes()).sum();
+    let target = total * ratio.clamp(0.0, 1.0);
+
+    let mut covered = 0.0;
+    for slot in &sorted {
+        if covered >= target - 1e-9 {
+            return Some(slot.price);
+        }
+        covered += slot.minutes();
+    }
+    sorted.last().map(|s| s.price)
+}
+
+/// Return all known slots from now and the given number of hours ahead.
+/// Spans into tomorrow once tomorrow's prices have been fetched.
+pub fn horizon_slots(
+    today: &[structs::Slot],
+    tomorrow: &[structs::Slot],
+    hours: u64,
+    now: OffsetDateTime,
+) -> Vec<structs::Slot> {
+    // No end when the horizon is past any date, rather than overflowing
+    let horizon = Duration::seconds(hours.saturating_mul(3600).min(i64::MAX as u64) as i64);
+    let horizon_end = now.checked_add(horizon);
+
+    today
+        .iter()
+        .chain(tomorrow)
+        .filter(|slot| slot.end > now && horizon_end.is_none_or(|end| slot.start < end))
+        .cloned()
+        .collect()
+}
+
+/// Parse the price data into slots
+pub fn slots(json: &Value, currency: &str) -> Vec<structs::Slot> {
+    let Some(array) = json.as_array() else {
+        return vec![];
+    };
 
-    let mut prices: Vec<f64> = array
+    array
         .iter()
-        .filter_map(|obj| obj.as_object()?.get(currency)?.as_f64())
-        .collect();
+        .filter_map(|obj| {
+            Some(structs::Slot {
+                start: parse_local_datetime(obj.get("time_start")?.as_str()?)?,
+                end: parse_local_datetime(obj.get("time_end")?.as_str()?)?,
+                price: obj.get(currency)?.as_f64()?,
+            })
+        })
+        .collect()
+}
+
+/// Aggregate slots into hourly averages, weighted by slot length
+pub fn hourly(slots: &[structs::Slot]) -> Vec<structs::Slot> {
+    let mut hours: Vec<structs::Slot> = vec![];
+    let mut weights: Vec<f64> = vec![];
 
-    prices.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    prices.get(index).copied()
+    for slot in slots {
+        let Ok(start) = slot
+            .start
+            .replace_minute(0)
+            .and_then(|t| t.replace_second(0))
+        else {
+            continue;
+        };
+        let start = start.replace_nanosecond(0).unwrap_or(start);
+
+        match hours.iter().position(|h| h.start == start) {
+            Some(i) => {
+                hours[i].price += slot.price * slot.minutes();
+                weights[i] += slot.minutes();
+            }
+            None => {
+                hours.push(structs::Slot {
+                    start,
+                    end: start + TimeDuration::from_secs(3600),
+                    price: slot.price * slot.minutes(),
+                });
+                weights.push(slot.minutes());
+            }
+        }
+    }
+
+    for (hour, weight) in hours.iter_mut().zip(weights) {
+        if weight > 0.0 {
+            hour.price /= weight;
+        }
+    }
+    hours
 }
 
-fn extract_valid_price(obj: &Value, currency: &str) -> Option<f64> {
+fn extract_valid_price(obj: &Value, currency: &str, now: OffsetDateTime) -> Option<f64> {
     let price = obj.get(currency)?.as_f64()?;
     let start_str = obj.get("time_start")?.as_str()?;
     let end_str = obj.get("time_end")?.as_str()?;
@@ -131,8 +580,6 @@ fn extract_valid_price(obj: &Value, currency: &str) -> Option<f64> {
     let start = parse_local_datetime(start_str)?;
     let end = parse_local_datetime(end_str)?;
 
-    let now = OffsetDateTime::now_local().ok()?;
-
     if now >= start && now < end {
         Some(price)
     } else {
@@ -152,7 +599,277 @@ pub fn total_price(spot: f64, config: &structs::Config) -> f64 {
     total * (1.0 + config.vat)
 }
 
+/// Whether the spot price is below the negative price threshold, on spot or total price
+pub fn is_negative(spot: f64, config: &structs::Config) -> bool {
+    let price = if config.negative_total {
+        total_price(spot, config)
+    } else {
+        spot
+    };
+    price < config.negative_price
+}
+
 /// Parse RFC3339 timestamp into local OffsetDateTime
 pub fn parse_local_datetime(s: &str) -> Option<OffsetDateTime> {
     OffsetDateTime::parse(s, &Rfc3339).ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::tests::{config, json_of, midnight, slots};
+
+    /// Slots a device with this trigger price runs, it is On below the trigger
+    fn on_slots(slots: &[structs::Slot], trigger: f64) -> usize {
+        slots.iter().filter(|s| s.price < trigger).count()
+    }
+
+    fn day_of(minutes: i64) -> Vec<structs::Slot> {
+        let count = 24 * 60 / minutes;
+        // Distinct prices in a shuffled order
+        let prices: Vec<f64> = (0..count).map(|i| ((i * 7) % count) as f64).collect();
+        slots(minutes, &prices)
+    }
+
+    fn day() -> structs::Day {
+        structs::Day {
+            date: midnight().date(),
+            area: "SE3".to_string(),
+            currency: "SEK_per_kWh".to_string(),
+            urls: vec![],
+            file: PathBuf::new(),
+        }
+    }
+
+    /// Seconds until the day may be downloaded again
+    fn retry_in(day: &structs::Day) -> u64 {
+        backoff_remaining(day).unwrap_or_default().as_secs()
+    }
+
+    #[test]
+    fn retry_after_is_seconds_or_a_date() {
+        assert_eq!(parse_retry_after("120"), Some(TimeDuration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 5 "), Some(TimeDuration::from_secs(5)));
+        assert_eq!(parse_retry_after("soon"), None);
+
+        let at = OffsetDateTime::now_utc() + Duration::hours(1);
+        let wait = parse_retry_after(&at.format(&Rfc2822).unwrap()).unwrap();
+        assert!((3590..=3600).contains(&wait.as_secs()), "{wait:?}");
+        // HTTP dates are GMT, one in the past means no wait
+        assert!(parse_retry_after("Sun, 06 Nov 2095 08:49:37 GMT").is_some());
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        // FETCH is shared, each backoff test uses its own day
+        let mut day = day();
+        day.date = Date::from_calendar_date(2020, time::Month::January, 1).unwrap();
+        let max = TimeDuration::from_secs(60);
+
+        record_failure(&day, "down", None, max);
+        // 10 s +-20%
+        assert!((7..=12).contains(&retry_in(&day)), "{}", retry_in(&day));
+        for _ in 0..4 {
+            record_failure(&day, "down", None, max);
+        }
+        assert!((58..=60).contains(&retry_in(&day)), "{}", retry_in(&day));
+        assert_eq!(fetch_status().failing[&day.date.to_string()].failures, 5);
+    }
+
+    #[test]
+    fn retry_after_beyond_the_cap_wins() {
+        let mut day = day();
+        day.date = Date::from_calendar_date(2020, time::Month::January, 2).unwrap();
+
+        let retry_after = Some(TimeDuration::from_secs(600));
+        record_failure(&day, "busy", retry_after, TimeDuration::from_secs(60));
+        assert!((598..=600).contains(&retry_in(&day)), "{}", retry_in(&day));
+
+        record_success(&day);
+        assert_eq!(backoff_remaining(&day), None);
+    }
+
+    #[test]
+    fn ratio_rounds_up_to_whole_slots() {
+        let hours = day_of(60);
+        assert_eq!(on_slots(&hours, ratio_of(&hours, 0.25).unwrap()), 6);
+        // Earlier releases ran 9, floor(23 * 0.4)
+        assert_eq!(on_slots(&hours, ratio_of(&hours, 0.4).unwrap()), 10);
+        assert_eq!(on_slots(&hours, ratio_of(&hours, 0.0).unwrap()), 0);
+        // The most expensive slot is the trigger, so it stays Off
+        assert_eq!(on_slots(&hours, ratio_of(&hours, 1.0).unwrap()), 23);
+        assert_eq!(ratio_of(&hours, 2.0), ratio_of(&hours, 1.0));
+        assert_eq!(ratio_of(&[], 0.5), None);
+    }
+
+    #[test]
+    fn ratio_counts_minutes_not_slots() {
+        let quarters = day_of(15);
+        assert_eq!(on_slots(&quarters, ratio_of(&quarters, 0.25).unwrap()), 24);
+        assert_eq!(on_slots(&quarters, ratio_of(&quarters, 0.4).unwrap()), 39);
+
+        // Two cheap hours and eight cheap quarters cover four hours
+        let mut mixed = slots(60, &[1.0, 2.0]);
+        mixed.extend(structs::tests::slots_from(
+            mixed[1].end,
+            15,
+            &[3.0, 3.0, 3.0, 3.0, 4.0, 4.0, 4.0, 4.0, 9.0, 9.0, 9.0, 9.0],
+        ));
+        mixed.extend(structs::tests::slots_from(mixed[13].end, 60, &[10.0; 4]));
+        // 9 hours in total, 4 of them is 0.44
+        assert_eq!(ratio_of(&mixed, 4.0 / 9.0), Some(9.0));
+    }
+
+    #[test]
+    fn horizon_spans_midnight() {
+        let today = day_of(60);
+        let tomorrow = structs::tests::slots_from(today[23].end, 60, &[1.0; 24]);
+        let now = today[20].start + Duration::minutes(30);
+
+        let upcoming = horizon_slots(&today, &tomorrow, 6, now);
+        // The current slot, the rest of today and 2 hours of tomorrow
+        assert_eq!(upcoming.len(), 7);
+        assert_eq!(upcoming[0].start, today[20].start);
+        assert_eq!(upcoming[6].start, tomorrow[2].start);
+
+        // Without tomorrow's prices the horizon ends at midnight
+        assert_eq!(horizon_slots(&today, &[], 6, now).len(), 4);
+    }
+
+    #[test]
+    fn horizon_past_any_date_takes_everything() {
+        let today = day_of(60);
+        let tomorrow = structs::tests::slots_from(today[23].end, 60, &[1.0; 24]);
+        assert_eq!(
+            horizon_slots(&today, &tomorrow, u64::MAX, midnight()).len(),
+            48
+        );
+    }
+
+    #[test]
+    fn valid_prices_cover_the_day() {
+        assert!(validate_prices(&json_of(&day_of(60)), &day()).is_ok());
+        assert!(validate_prices(&json_of(&day_of(15)), &day()).is_ok());
+    }
+
+    #[test]
+    fn invalid_prices_are_rejected() {
+        let hours = day_of(60);
+
+        assert!(validate_prices(&json!({}), &day()).is_err());
+        assert!(validate_prices(&json!([]), &day()).is_err());
+
+        let mut missing = json_of(&hours);
+        missing[5].as_object_mut().unwrap().remove("SEK_per_kWh");
+        assert!(validate_prices(&missing, &day()).is_err());
+
+        let mut gap = hours.clone();
+        gap.remove(5);
+        assert!(validate_prices(&json_of(&gap), &day()).is_err());
+
+        assert!(validate_prices(&json_of(&hours[..23]), &day()).is_err());
+
+        let next_day = structs::tests::slots_from(hours[23].end, 60, &[1.0; 24]);
+        assert!(validate_prices(&json_of(&next_day), &day()).is_err());
+    }
+
+    #[test]
+    fn hourly_averages_quarters() {
+        let quarters = slots(15, &[1.0, 2.0, 3.0, 6.0, 4.0, 4.0, 4.0, 4.0]);
+        let hours = hourly(&quarters);
+        assert_eq!(hours.len(), 2);
+        assert_eq!(hours[0].price, 3.0);
+        assert_eq!(hours[1].price, 4.0);
+        assert_eq!(hours[0].start, midnight());
+        assert_eq!(hours[0].minutes(), 60.0);
+        assert_eq!(hours[1].start, quarters[4].start);
+
+        // Hourly slots are left as they are
+        let same = hourly(&slots(60, &[5.0, 7.0]));
+        assert_eq!(same.iter().map(|s| s.price).collect::<Vec<_>>(), [5.0, 7.0]);
+    }
+
+    #[test]
+    fn hourly_weights_by_slot_length() {
+        let mut mixed = slots(30, &[2.0]);
+        mixed.extend(structs::tests::slots_from(mixed[0].end, 15, &[5.0, 5.0]));
+        assert_eq!(hourly(&mixed)[0].price, 3.5);
+    }
+
+    #[test]
+    fn cache_dir_is_always_a_pricecontrol_directory() {
+        let mut config = config();
+        config.cache_dir = "/var/cache".to_string();
+        assert_eq!(cache_dir(&config), PathBuf::from("/var/cache/pricecontrol"));
+
+        config.cache_dir = String::new();
+        assert!(cache_dir(&config).ends_with("pricecontrol"));
+    }
+
+    #[test]
+    fn only_price_files_are_cache_files() {
+        for name in [
+            "elprisetjustnu-se_2026-10-18_SE3_SEK_per_kWh.json",
+            "localhost-8099_2026-10-18_SE3_EUR_per_kWh.json.bad",
+        ] {
+            assert!(is_cache_file(Path::new(name)), "{name}");
+        }
+        for name in [
+            "notes.json",
+            "settings_backup.json",
+            "elprisetjustnu-se_2026-10-18_SE3_SEK_per_kWh.txt",
+            "elprisetjustnu-se_18-10-2026_SE3_SEK_per_kWh.json",
+            "elprisetjustnu.se_2026-10-18_SE3_SEK_per_kWh.json",
+            "elprisetjustnu-se_2026-10-18_SE3.json",
+            "report.bad",
+        ] {
+            assert!(!is_cache_file(Path::new(name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn cache_files_are_replaced_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut day = day();
+        day.file = dir.path().join("new").join("prices.json");
+        let prices = json_of(&day_of(60));
+
+        // The directory is created and nothing but the file is left in it
+        fs::write(dir.path().join("other"), "kept").unwrap();
+        save_prices_to_file(&json!(["old"]), &day.file).unwrap();
+        save_prices_to_file(&prices, &day.file).unwrap();
+        let names: Vec<_> = fs::read_dir(day.file.parent().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["prices.json"]);
+        assert_eq!(try_load_local(&day).unwrap(), prices);
+    }
+
+    #[test]
+    fn invalid_cache_files_are_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut day = day();
+        day.file = dir.path().join("prices.json");
+        let bad = dir.path().join("prices.json.bad");
+
+        // A missing file is just a miss
+        assert!(try_load_local(&day).is_err());
+        assert!(!bad.exists());
+
+        let truncated = &json_of(&day_of(60)).to_string()[..100];
+        fs::write(&day.file, truncated).unwrap();
+        assert!(try_load_local(&day).is_err());
+        assert!(!day.file.exists());
+        assert_eq!(fs::read_to_string(&bad).unwrap(), truncated);
+
+        // Valid JSON for the wrong day is moved aside too
+        let next_day = structs::tests::slots_from(midnight() + Duration::days(1), 60, &[1.0; 24]);
+        let wrong_day = json_of(&next_day).to_string();
+        fs::write(&day.file, &wrong_day).unwrap();
+        assert!(try_load_local(&day).is_err());
+        assert!(!day.file.exists());
+        assert_eq!(fs::read_to_string(&bad).unwrap(), wrong_day);
+    }
+}
@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Variables a rule can use, numbers unless noted
+pub const VARIABLES: &[&str] = &[
+    "spot",       // current spot price
+    "total",      // current price incl fees and vat
+    "average",    // today's average spot price
+    "rank",       // rank of the current slot today, 1 = cheapest
+    "rank_today", // alias of rank, parsed as rank
+    "percentile", // share of today cheaper than now, 0-100
+    "hour",       // 0-23
+    "minute",     // 0-59
+    "weekday",    // 1 = Monday, 7 = Sunday
+    "tomorrow",   // bool, tomorrow's prices are known
+    "negative",   // bool, price below negative_price
+];
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RuleError {
+    #[error("Syntax error at position {0}: {1}")]
+    Syntax(usize, String),
+
+    #[error("Unknown variable: {0}")]
+    UnknownVariable(String),
+
+    #[error("Unknown device: {0}")]
+    UnknownDevice(String),
+
+    #[error("Unknown function: {0}")]
+    UnknownFunction(String),
+
+    #[error("Type error: {0}")]
+    Type(String),
+}
+
+/// A parsed rule expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Bool(bool),
+    Variable(String),
+    /// State of another device, on("name")
+    DeviceOn(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Or,
+    And,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    Bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Bool(bool),
+}
+
+/// Values of the rule variables at one point in time
+#[derive(Debug, Default, Clone)]
+pub struct Context {
+    pub numbers: HashMap<&'static str, f64>,
+    pub bools: HashMap<&'static str, bool>,
+    /// Device name to On
+    pub devices: HashMap<String, bool>,
+}
+
+impl Expr {
+    /// Evaluate the rule, None if a variable has no value right now
+    pub fn eval(&self, ctx: &Context) -> Option<Value> {
+        Some(match self {
+            Expr::Number(n) => Value::Number(*n),
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::Variable(name) => match ctx.numbers.get(name.as_str()) {
+                Some(n) => Value::Number(*n),
+                None => Value::Bool(*ctx.bools.get(name.as_str())?),
+            },
+            Expr::DeviceOn(name) => Value::Bool(*ctx.devices.get(name)?),
+            Expr::Not(e) => Value::Bool(!e.eval(ctx)?.as_bool()),
+            Expr::Neg(e) => Value::Number(-e.eval(ctx)?.as_number()),
+            Expr::Binary(op @ (Op::Or | Op::And), a, b) => {
+                // Either side decides on its own, so a missing value on the other doesn't
+                // matter: None or true is true, None and false is false
+                let decides = Value::Bool(*op == Op::Or);
+                let a = a.eval(ctx);
+                if a == Some(decides) {
+                    return a;
+                }
+                let b = b.eval(ctx);
+                if b == Some(decides) {
+                    return b;
+                }
+                a?;
+                b?
+            }
+            Expr::Binary(op, a, b) => {
+                let a = a.eval(ctx)?;
+                let b = b.eval(ctx)?;
+                match op {
+                    Op::Or | Op::And => unreachable!("handled above"),
+                    Op::Lt => Value::Bool(a.as_number() < b.as_number()),
+                    Op::Le => Value::Bool(a.as_number() <= b.as_number()),
+                    Op::Gt => Value::Bool(a.as_number() > b.as_number()),
+                    Op::Ge => Value::Bool(a.as_number() >= b.as_number()),
+                    Op::Eq => Value::Bool(a == b),
+                    Op::Ne => Value::Bool(a != b),
+                    Op::Add => Value::Number(a.as_number() + b.as_number()),
+                    Op::Sub => Value::Number(a.as_number() - b.as_number()),
+                    Op::Mul => Value::Number(a.as_number() * b.as_number()),
+                    Op::Div => Value::Number(a.as_number() / b.as_number()),
+                }
+            }
+        })
+    }
+
+    /// Names of the devices the rule refers to
+    pub fn device_names(&self) -> Vec<String> {
+        match self {
+            Expr::DeviceOn(name) => vec![name.clone()],
+            Expr::Not(e) | Expr::Neg(e) => e.device_names(),
+            Expr::Binary(_, a, b) => {
+                let mut names = a.device_names();
+                names.extend(b.device_names());
+                names
+            }
+            _ => vec![],
+        }
+    }
+
+    fn check(&self) -> Result<Type, RuleError> {
+        match self {
+            Expr::Number(_) => Ok(Type::Number),
+            Expr::Bool(_) | Expr::DeviceOn(_) => Ok(Type::Bool),
+            Expr::Variable(name) if matches!(name.as_str(), "tomorrow" | "negative") => {
+                Ok(Type::Bool)
+            }
+            Expr::Variable(_) => Ok(Type::Number),
+            Expr::Not(e) => expect(e, Type::Bool, "not"),
+            Expr::Neg(e) => expect(e, Type::Number, "-"),
+            Expr::Binary(op, a, b) => match op {
+                Op::Or | Op::And => {
+                    expect(a, Type::Bool, "and/or")?;
+                    expect(b, Type::Bool, "and/or")
+                }
+                Op::Eq | Op::Ne => {
+                    if a.check()? != b.check()? {
+                        return Err(RuleError::Type("comparing a number to a bool".into()));
+                    }
+                    Ok(Type::Bool)
+                }
+                Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                    expect(a, Type::Number, "comparison")?;
+                    expect(b, Type::Number, "comparison")?;
+                    Ok(Type::Bool)
+                }
+                Op::Add | Op::Sub | Op::Mul | Op::Div => {
+                    expect(a, Type::Number, "arithmetic")?;
+                    expect(b, Type::Number, "arithmetic")
+                }
+            },
+        }
+    }
+}
+
+fn expect(e: &Expr, want: Type, what: &str) -> Result<Type, RuleError> {
+    let got = e.check()?;
+    if got == want {
+        Ok(want)
+    } else {
+        Err(RuleError::Type(format!(
+            "{} expects {:?}, got {:?}",
+            what, want, got
+        )))
+    }
+}
+
+impl Value {
+    fn as_bool(self) -> bool {
+        matches!(self, Value::Bool(true))
+    }
+
+    fn as_number(self) -> f64 {
+        match self {
+            Value::Number(n) => n,
+            Value::Bool(b) => b as u8 as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Str(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+/// Parse a rule, e.g. `spot < 0.5 or (rank_today <= 6 and hour >= 22)`
+pub fn parse(source: &str) -> Result<Expr, RuleError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.or()?;
+
+    if let Some((pos, token)) = parser.tokens.get(parser.pos) {
+        return Err(RuleError::Syntax(*pos, format!("unexpected {:?}", token)));
+    }
+    if expr.check()? != Type::Bool {
+        return Err(RuleError::Type("the rule must be true or false".into()));
+    }
+    Ok(expr)
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, RuleError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let n = text
+                .parse()
+                .map_err(|_| RuleError::Syntax(start, format!("bad number {}", text)))?;
+            tokens.push((start, Token::Number(n)));
+            continue;
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((start, Token::Ident(chars[start..i].iter().collect())));
+            continue;
+        }
+
+        if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                i += 1;
+            }
+            if i == chars.len() {
+                return Err(RuleError::Syntax(start, "unterminated string".into()));
+            }
+            tokens.push((start, Token::Str(chars[start + 1..i].iter().collect())));
+            i += 1;
+            continue;
+        }
+
+        let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+        let op = match two.as_str() {
+            "<=" => Some("<="),
+            ">=" => Some(">="),
+            "==" => Some("=="),
+            "!=" => Some("!="),
+            "&&" => Some("and"),
+            "||" => Some("or"),
+            _ => None,
+        };
+        if let Some(op) = op {
+            tokens.push((start, Token::Op(op)));
+            i += 2;
+            continue;
+        }
+
+        let token = match c {
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '<' => Token::Op("<"),
+            '>' => Token::Op(">"),
+            '+' => Token::Op("+"),
+            '-' => Token::Op("-"),
+            '*' => Token::Op("*"),
+            '/' => Token::Op("/"),
+            '!' => Token::Op("not"),
+            _ => return Err(RuleError::Syntax(start, format!("unexpected '{}'", c))),
+        };
+        tokens.push((start, token));
+        i += 1;
+    }
+
+    // Words are operators too
+    for (_, token) in tokens.iter_mut() {
+        if let Token::Ident(word) = token {
+            match word.as_str() {
+                "and" => *token = Token::Op("and"),
+                "or" => *token = Token::Op("or"),
+                "not" => *token = Token::Op("not"),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or(0, |(p, _)| *p)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn eat_op(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, RuleError> {
+        let mut left = self.and()?;
+        while self.eat_op(&["or"]).is_some() {
+            left = Expr::Binary(Op::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, RuleError> {
+        let mut left = self.not()?;
+        while self.eat_op(&["and"]).is_some() {
+            left = Expr::Binary(Op::And, Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, RuleError> {
+        if self.eat_op(&["not"]).is_some() {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, RuleError> {
+        let left = self.sum()?;
+        let op = match self.eat_op(&["<", "<=", ">", ">=", "==", "!="]) {
+            Some("<") => Op::Lt,
+            Some("<=") => Op::Le,
+            Some(">") => Op::Gt,
+            Some(">=") => Op::Ge,
+            Some("==") => Op::Eq,
+            Some("!=") => Op::Ne,
+            _ => return Ok(left),
+        };
+        Ok(Expr::Binary(op, Box::new(left), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, RuleError> {
+        let mut left = self.product()?;
+        while let Some(op) = self.eat_op(&["+", "-"]) {
+            let op = if op == "+" { Op::Add } else { Op::Sub };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expr, RuleError> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.eat_op(&["*", "/"]) {
+            let op = if op == "*" { Op::Mul } else { Op::Div };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, RuleError> {
+        if self.eat_op(&["-"]).is_some() {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, RuleError> {
+        let pos = self.position();
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(RuleError::Syntax(self.position(), "expected ')'".into())),
+                }
+            }
+            Some(Token::Ident(name)) if self.peek() == Some(&Token::LParen) => {
+                self.pos += 1;
+                let arg = match self.next() {
+                    Some(Token::Str(arg)) => arg,
+                    _ => {
+                        return Err(RuleError::Syntax(
+                            self.position(),
+                            "expected a quoted device name".into(),
+                        ))
+                    }
+                };
+                if self.next() != Some(Token::RParen) {
+                    return Err(RuleError::Syntax(self.position(), "expected ')'".into()));
+                }
+                match name.as_str() {
+                    "on" => Ok(Expr::DeviceOn(arg)),
+                    "off" => Ok(Expr::Not(Box::new(Expr::DeviceOn(arg)))),
+                    _ => Err(RuleError::UnknownFunction(name)),
+                }
+            }
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Expr::Bool(true)),
+                "false" => Ok(Expr::Bool(false)),
+                "rank_today" => Ok(Expr::Variable("rank".to_string())),
+                _ if VARIABLES.contains(&name.as_str()) => Ok(Expr::Variable(name)),
+                _ => Err(RuleError::UnknownVariable(name)),
+            },
+            Some(token) => Err(RuleError::Syntax(pos, format!("unexpected {:?}", token))),
+            None => Err(RuleError::Syntax(pos, "unexpected end of rule".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> Context {
+        let mut ctx = Context::default();
+        ctx.numbers.insert("spot", 0.4);
+        ctx.numbers.insert("rank", 3.0);
+        ctx.numbers.insert("hour", 22.0);
+        ctx.bools.insert("tomorrow", false);
+        ctx.devices.insert("heater".to_string(), true);
+        ctx
+    }
+
+    fn eval(rule: &str, ctx: &Context) -> Option<Value> {
+        parse(rule).unwrap().eval(ctx)
+    }
+
+    #[test]
+    fn parses_variables_numbers_and_devices() {
+        assert_eq!(
+            parse("spot < 0.5").unwrap(),
+            Expr::Binary(
+                Op::Lt,
+                Box::new(Expr::Variable("spot".into())),
+                Box::new(Expr::Number(0.5))
+            )
+        );
+        assert_eq!(
+            parse("off('heater')").unwrap(),
+            Expr::Not(Box::new(Expr::DeviceOn("heater".into())))
+        );
+        assert_eq!(
+            parse("on(\"a\") and on(\"b\")").unwrap().device_names(),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    fn rank_today_is_rank() {
+        assert_eq!(parse("rank_today <= 6"), parse("rank <= 6"));
+    }
+
+    #[test]
+    fn precedence() {
+        // and binds tighter than or
+        assert_eq!(
+            parse("true or false and false").unwrap(),
+            parse("true or (false and false)").unwrap()
+        );
+        // arithmetic before comparison, * before +
+        assert_eq!(
+            parse("spot + 1 * 2 > 3").unwrap(),
+            parse("(spot + (1 * 2)) > 3").unwrap()
+        );
+        // not applies to the comparison, - to the number
+        assert_eq!(
+            parse("not -spot < 0").unwrap(),
+            parse("not ((-spot) < 0)").unwrap()
+        );
+        assert_eq!(parse("1 - 2 - 3 == -4"), parse("((1 - 2) - 3) == -4"));
+    }
+
+    #[test]
+    fn rejects_bad_syntax() {
+        assert!(matches!(parse("spot <"), Err(RuleError::Syntax(..))));
+        assert!(matches!(parse("(spot < 1"), Err(RuleError::Syntax(..))));
+        assert!(matches!(parse("spot < 1 1"), Err(RuleError::Syntax(..))));
+        assert!(matches!(parse("on('heater"), Err(RuleError::Syntax(..))));
+        assert!(matches!(parse("on(heater)"), Err(RuleError::Syntax(..))));
+        assert!(matches!(parse("spot # 1"), Err(RuleError::Syntax(..))));
+        assert!(matches!(parse("1..2 < spot"), Err(RuleError::Syntax(..))));
+        assert_eq!(
+            parse("price < 1"),
+            Err(RuleError::UnknownVariable("price".into()))
+        );
+        assert_eq!(
+            parse("running('heater')"),
+            Err(RuleError::UnknownFunction("running".into()))
+        );
+    }
+
+    #[test]
+    fn rejects_type_errors() {
+        for rule in [
+            "spot",
+            "spot + 1",
+            "spot and true",
+            "not spot",
+            "tomorrow < 1",
+            "tomorrow == 1",
+            "-tomorrow == 1",
+            "on('heater') + 1 > 0",
+        ] {
+            assert!(matches!(parse(rule), Err(RuleError::Type(_))), "{rule}");
+        }
+    }
+
+    #[test]
+    fn evaluates() {
+        let ctx = ctx();
+        assert_eq!(eval("spot < 0.5", &ctx), Some(Value::Bool(true)));
+        assert_eq!(
+            eval("rank <= 2 or (hour >= 22 and on('heater'))", &ctx),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(eval("off('heater')", &ctx), Some(Value::Bool(false)));
+        assert_eq!(eval("spot * 10 - 4 == 0", &ctx), Some(Value::Bool(true)));
+        assert_eq!(eval("not tomorrow", &ctx), Some(Value::Bool(true)));
+        assert_eq!(eval("tomorrow != false", &ctx), Some(Value::Bool(false)));
+    }
+
+    #[test]
+    fn missing_values_propagate() {
+        let ctx = ctx();
+        // percentile, negative and unknown devices have no value
+        assert_eq!(eval("percentile < 50", &ctx), None);
+        assert_eq!(eval("not negative", &ctx), None);
+        assert_eq!(eval("on('pump')", &ctx), None);
+        assert_eq!(eval("percentile < 50 and spot < 1", &ctx), None);
+        assert_eq!(eval("percentile < 50 or spot > 1", &ctx), None);
+    }
+
+    #[test]
+    fn and_or_short_circuit_on_both_sides() {
+        let ctx = ctx();
+        for (rule, value) in [
+            ("negative or spot < 1", true),
+            ("spot < 1 or negative", true),
+            ("negative and spot > 1", false),
+            ("spot > 1 and negative", false),
+        ] {
+            assert_eq!(eval(rule, &ctx), Some(Value::Bool(value)), "{rule}");
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::debug;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+use crate::notify::{Security, Smtp};
+
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Send a plain text mail, with STARTTLS, implicit TLS or unencrypted as configured
+pub fn send(config: &Smtp, subject: &str, body: &str) -> Result<()> {
+    let stream = TcpStream::connect((config.server.as_str(), config.port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    match config.security {
+        Security::Tls => {
+            let mut session = Session::new(tls(stream, &config.server)?);
+            session.expect(220)?;
+            session.ehlo()?;
+            session.deliver(config, subject, body)
+        }
+        Security::Starttls => {
+            let mut session = Session::new(stream);
+            session.expect(220)?;
+            session.ehlo()?;
+            session.command("STARTTLS", 220)?;
+            let mut session = Session::new(tls(session.into_inner(), &config.server)?);
+            session.ehlo()?;
+            session.deliver(config, subject, body)
+        }
+        Security::None => {
+            let mut session = Session::new(stream);
+            session.expect(220)?;
+            session.ehlo()?;
+            session.deliver(config, subject, body)
+        }
+    }
+}
+
+fn tls(stream: TcpStream, server: &str) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let name = ServerName::try_from(server.to_string())?;
+    let connection = ClientConnection::new(Arc::new(config), name)?;
+    Ok(StreamOwned::new(connection, stream))
+}
+
+struct Session<S: Read + Write> {
+    stream: BufReader<S>,
+    /// The server takes 8 bit bodies, from its EHLO reply
+    eightbit: bool,
+}
+
+impl<S: Read + Write> Session<S> {
+    fn new(stream: S) -> Self {
+        Session {
+            stream: BufReader::new(stream),
+            eightbit: false,
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    /// Read a possibly multi-line reply and check its code
+    fn expect(&mut self, code: u16) -> Result<String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(anyhow!("SMTP server closed the connection"));
+            }
+            reply.push_str(&line);
+            // "250-" continues, "250 " ends the reply
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+        debug!("SMTP: {}", reply.trim_end());
+
+        match reply.get(..3).and_then(|c| c.parse::<u16>().ok()) {
+            Some(c) if c == code => Ok(reply),
+            _ => Err(anyhow!("SMTP server replied {}", reply.trim_end())),
+        }
+    }
+
+    fn command(&mut self, command: &str, code: u16) -> Result<String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.expect(code)
+    }
+
+    fn ehlo(&mut self) -> Result<()> {
+        let reply = self.command("EHLO [127.0.0.1]", 250)?;
+        self.eightbit = has_extension(&reply, "8BITMIME");
+        Ok(())
+    }
+
+    fn deliver(&mut self, config: &Smtp, subject: &str, body: &str) -> Result<()> {
+        if !config.username.is_empty() {
+            let credentials = format!("\0{}\0{}", config.username, config.password);
+            self.command(&format!("AUTH PLAIN {}", STANDARD.encode(credentials)), 235)?;
+        }
+
+        // 8 bit only where the server announced it, quoted-printable otherwise
+        let (encoding, body) = if body.is_ascii() {
+            ("7bit", body.to_string())
+        } else if self.eightbit {
+            ("8bit", body.to_string())
+        } else {
+            ("quoted-printable", quoted_printable(body))
+        };
+
+        self.command(&mail_from(&config.from, encoding), 250)?;
+        for to in &config.to {
+            self.command(&format!("RCPT TO:<{}>", to), 250)?;
+        }
+        self.command("DATA", 354)?;
+
+        let date = OffsetDateTime::now_local()
+            .unwrap_or_else(|_| OffsetDateTime::now_utc())
+            .format(&Rfc2822)?;
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: {}\r\n\r\n",
+            config.from,
+            config.to.join(", "),
+            encode_header(subject),
+            date,
+            encoding
+        );
+        for line in body.lines() {
+            // A leading dot would end the message early
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push('.');
+        self.command(&message, 250)?;
+
+        let _ = self.command("QUIT", 221);
+        Ok(())
+    }
+}
+
+/// Non-ASCII headers are sent as RFC 2047 encoded words
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+/// Whether an EHLO reply lists the extension, e.g. "250-8BITMIME"
+fn has_extension(reply: &str, extension: &str) -> bool {
+    reply.lines().any(|line| {
+        line.get(4..)
+            .and_then(|keywords| keywords.split_whitespace().next())
+            .is_some_and(|k| k.eq_ignore_ascii_case(extension))
+    })
+}
+
+/// Quoted-printable body (RFC 2045), lines kept and wrapped at 76 characters
+fn quoted_printable(body: &str) -> String {
+    let mut out = String::new();
+    for line in body.lines() {
+        let bytes = line.as_bytes();
+        let mut width = 0;
+        for (i, &b) in bytes.iter().enumerate() {
+            let last = i + 1 == bytes.len();
+            let encoded = match b {
+                // Trailing white space would be stripped in transit
+                b' ' | b'\t' if last => format!("={:02X}", b),
+                b' ' | b'\t' | 33..=60 | 62..=126 => (b as char).to_string(),
+                _ => format!("={:02X}", b),
+            };
+            // A soft line break takes the last column
+            if width + encoded.len() > 75 {
+                out.push_str("=\r\n");
+                width = 0;
+            }
+            width += encoded.len();
+            out.push_str(&encoded);
+        }
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// An 8 bit body has to be declared up front, RFC 6152
+fn mail_from(from: &str, encoding: &str) -> String {
+    if encoding == "8bit" {
+        format!("MAIL FROM:<{}> BODY=8BITMIME", from)
+    } else {
+        format!("MAIL FROM:<{}>", from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_8bitmime_in_ehlo_reply() {
+        let reply = "250-mail.example.com\r\n250-PIPELINING\r\n250-8BITMIME\r\n250 SMTPUTF8\r\n";
+        assert!(has_extension(reply, "8BITMIME"));
+        assert!(has_extension(reply, "smtputf8"));
+        assert!(!has_extension(reply, "STARTTLS"));
+        assert!(!has_extension("250 mail.example.com\r\n", "8BITMIME"));
+    }
+
+    #[test]
+    fn mail_from_declares_8bit_bodies() {
+        assert_eq!(
+            mail_from("rpc@example.com", "8bit"),
+            "MAIL FROM:<rpc@example.com> BODY=8BITMIME"
+        );
+        assert_eq!(
+            mail_from("rpc@example.com", "7bit"),
+            "MAIL FROM:<rpc@example.com>"
+        );
+        assert_eq!(
+            mail_from("rpc@example.com", "quoted-printable"),
+            "MAIL FROM:<rpc@example.com>"
+        );
+    }
+
+    #[test]
+    fn quoted_printable_encodes_non_ascii_and_wraps() {
+        assert_eq!(
+            quoted_printable("Spot 0,52 öre/kWh = låg"),
+            "Spot 0,52 =C3=B6re/kWh =3D l=C3=A5g\r\n"
+        );
+        assert_eq!(quoted_printable("a \nb"), "a=20\r\nb\r\n");
+
+        let long = quoted_printable(&"ä".repeat(40));
+        let lines: Vec<&str> = long.split("\r\n").collect();
+        assert!(lines.iter().all(|l| l.len() <= 76));
+        assert!(lines[0].ends_with('='));
+        assert_eq!(long.replace("=\r\n", ""), "=C3=A4".repeat(40) + "\r\n");
+    }
+}
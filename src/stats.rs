@@ -0,0 +1,229 @@
+use anyhow::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{device_model, history, price, structs};
+
+const STATS_FILE: &str = "stats.json";
+
+/// Energy and cost per device, built from the time each device spends On
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Stats {
+    pub devices: BTreeMap<String, DeviceStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeviceStats {
+    /// Keyed by YYYY-MM-DD
+    pub days: BTreeMap<String, Usage>,
+    /// Keyed by YYYY-MM
+    pub months: BTreeMap<String, Usage>,
+    /// Today's On intervals, one per price slot
+    pub intervals: Vec<Interval>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Usage {
+    pub minutes: f64,
+    pub kwh: f64,
+    /// Spot price only
+    pub spot_cost: f64,
+    /// Including fees and VAT
+    pub total_cost: f64,
+    /// What the same energy would have cost at the day's average price, incl fees and VAT
+    pub average_cost: f64,
+    /// average_cost - total_cost
+    pub savings: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Interval {
+    /// Start of the price slot
+    pub slot: String,
+    pub start: String,
+    pub end: String,
+    pub spot: f64,
+    pub total: f64,
+}
+
+impl Usage {
+    fn add(&mut self, minutes: f64, kwh: f64, spot: f64, average: f64, config: &structs::Config) {
+        let total = price::total_price(spot, config);
+        let average = price::total_price(average, config);
+
+        self.minutes += minutes;
+        self.kwh += kwh;
+        self.spot_cost += kwh * spot;
+        self.total_cost += kwh * total;
+        self.average_cost += kwh * average;
+        self.savings = self.average_cost - self.total_cost;
+    }
+}
+
+impl Stats {
+    /// The stats saved by the last run, without days older than history_days
+    pub fn load(config: &structs::Config) -> Stats {
+        let path = path(config);
+        let mut stats: Stats = match fs::read_to_string(&path) {
+            Ok(json) => match serde_json::from_str(&json) {
+                Ok(stats) => stats,
+                Err(e) => {
+                    warn!("Ignoring invalid stats {}: {e}", path.display());
+                    return Stats::default();
+                }
+            },
+            Err(_) => return Stats::default(),
+        };
+        debug!("Loaded stats from {}", path.display());
+
+        if config.history_days > 0 {
+            let today = OffsetDateTime::now_local()
+                .unwrap_or_else(|_| OffsetDateTime::now_utc())
+                .date();
+            let cutoff = (today - time::Duration::days(config.history_days as i64)).to_string();
+            for device in stats.devices.values_mut() {
+                device.days.retain(|day, _| *day >= cutoff);
+                device
+                    .months
+                    .retain(|month, _| month.as_str() >= &cutoff[..7]);
+            }
+        }
+        stats
+    }
+
+    /// Write the stats next to the history, replacing the file atomically
+    pub fn save(&self, config: &structs::Config) -> Result<()> {
+        let path = path(config);
+        let dir = history::data_dir(config);
+        fs::create_dir_all(&dir)?;
+
+        let mut tmp = NamedTempFile::new_in(&dir)?;
+        tmp.write_all(serde_json::to_string(self)?.as_bytes())?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+
+    /// Add the elapsed time to every device that is On, at the current slot's price
+    pub fn record(
+        &mut self,
+        devices: &device_model::Devices,
+        today_spot_prices: &serde_json::Value,
+        config: &structs::Config,
+        elapsed: Duration,
+    ) {
+        let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        self.record_at(devices, today_spot_prices, config, elapsed, now);
+    }
+
+    fn record_at(
+        &mut self,
+        devices: &device_model::Devices,
+        today_spot_prices: &serde_json::Value,
+        config: &structs::Config,
+        elapsed: Duration,
+        now: OffsetDateTime,
+    ) {
+        let slots = price::slots(today_spot_prices, &config.currency);
+        let Some(slot) = slots.iter().find(|s| now >= s.start && now < s.end) else {
+            return;
+        };
+        let average = price::average_of(&slots).unwrap_or(slot.price);
+
+        // Don't count long gaps, e.g. after a suspend, as On time
+        let elapsed = elapsed.min(Duration::from_secs(config.interval * 2));
+        let minutes = elapsed.as_secs_f64() / 60.0;
+
+        let day = format!("{}-{:02}-{:02}", now.year(), now.month() as u8, now.day());
+        let month = day[..7].to_string();
+        let slot_start = slot.start.format(&Rfc3339).unwrap_or_default();
+        let start = (now - elapsed).format(&Rfc3339).unwrap_or_default();
+        let now_str = now.format(&Rfc3339).unwrap_or_default();
+
+        for device in &devices.device {
+            let stats = self.devices.entry(device.name.clone()).or_default();
+
+            // Intervals are kept for today only
+            if !stats.days.contains_key(&day) {
+                stats.intervals.clear();
+            }
+            let usage = stats.days.entry(day.clone()).or_default();
+
+            if device.state != device_model::State::On {
+                continue;
+            }
+
+            let kwh = device.power_kw * minutes / 60.0;
+            usage.add(minutes, kwh, slot.price, average, config);
+            stats
+                .months
+                .entry(month.clone())
+                .or_default()
+                .add(minutes, kwh, slot.price, average, config);
+
+            match stats.intervals.last_mut() {
+                Some(last) if last.slot == slot_start => last.end = now_str.clone(),
+                _ => stats.intervals.push(Interval {
+                    slot: slot_start.clone(),
+                    start: start.clone(),
+                    end: now_str.clone(),
+                    spot: slot.price,
+                    total: price::total_price(slot.price, config),
+                }),
+            }
+        }
+    }
+}
+
+fn path(config: &structs::Config) -> PathBuf {
+    history::data_dir(config).join(STATS_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::tests::{config, json_of, midnight, slots};
+
+    #[test]
+    fn records_on_time_at_the_slot_price() {
+        let devices: device_model::Devices = toml::from_str(
+            r#"
+            [[device]]
+            name = "heater"
+            power_kw = 2.0
+            state = "On"
+            [[device]]
+            name = "lamp"
+            power_kw = 1.0
+            state = "Off"
+            "#,
+        )
+        .unwrap();
+        let prices = json_of(&slots(60, &[1.0, 3.0]));
+        let mut stats = Stats::default();
+        let now = midnight() + time::Duration::minutes(30);
+
+        // Gaps longer than two intervals only count as two
+        stats.record_at(&devices, &prices, &config(), Duration::from_secs(3600), now);
+
+        let heater = &stats.devices["heater"];
+        let day = &heater.days["2026-03-10"];
+        assert_eq!(day.minutes, 2.0);
+        assert!((day.kwh - 2.0 * 2.0 / 60.0).abs() < 1e-9);
+        assert!((day.spot_cost - day.kwh * 1.0).abs() < 1e-9);
+        assert!(heater.months.contains_key("2026-03"));
+        assert_eq!(heater.intervals.len(), 1);
+        assert_eq!(stats.devices["lamp"].days["2026-03-10"].minutes, 0.0);
+
+        // No slot at this time, nothing is counted
+        let later = midnight() + time::Duration::hours(5);
+        stats.record_at(&devices, &prices, &config(), Duration::from_secs(60), later);
+        assert_eq!(stats.devices["heater"].days["2026-03-10"].minutes, 2.0);
+    }
+}
@@ -1,16 +1,37 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use thiserror::Error;
-use time::Date;
+use time::{Date, OffsetDateTime, Time};
 
-use crate::device_model;
+use crate::{device_model, notify, rules, stats};
 
 #[derive(Debug)]
 pub struct Day {
     pub date: Date,
-    pub url: String,
-    pub file: String,
+    pub area: String,
+    pub currency: String,
+    /// The api url first, then the fallbacks
+    pub urls: Vec<String>,
+    /// Path of the cached price file
+    pub file: PathBuf,
+}
+
+/// A price slot of any length, 15 minutes or an hour
+#[derive(Debug, Clone)]
+pub struct Slot {
+    pub start: OffsetDateTime,
+    pub end: OffsetDateTime,
+    pub price: f64,
+}
+
+impl Slot {
+    /// Length of the slot in minutes
+    pub fn minutes(&self) -> f64 {
+        (self.end - self.start).as_seconds_f64() / 60.0
+    }
 }
 
 #[derive(Debug, Error)]
@@ -20,6 +41,9 @@ pub enum ConfigError {
 
     #[error("Failed to parse config: {0}")]
     Parse(#[from] toml::de::Error),
+
+    #[error("Invalid config: {0}")]
+    Invalid(String),
 }
 
 #[derive(Debug, Error)]
@@ -29,19 +53,42 @@ pub enum DeviceError {
 
     #[error("Failed to parse devices: {0}")]
     Parse(#[from] toml::de::Error),
+
+    #[error("Unknown device {1} in {0}")]
+    UnknownDevice(String, String),
+
+    #[error("Rule error in device {0}: {1}")]
+    Rule(String, rules::RuleError),
+
+    #[error("Invalid {1} in device {0}")]
+    Invalid(String, String),
 }
 
 /// The program config from config file
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
     pub api: String,
+    /// Tried in order when api fails, same url layout as api
+    #[serde(default)]
+    pub api_fallback: Vec<String>,
     pub area: String,
     pub currency: String,
     pub interval: u64,
-    pub webui_port: u64,
+    pub webui_port: u16,
+    /// Addresses the web UI listens on, IPv4 or IPv6 with or without a port. Empty disables it
+    #[serde(default = "default_webui_bind")]
+    pub webui_bind: Vec<String>,
+    /// PEM certificate chain and private key, with both set the web UI is served over HTTPS
+    #[serde(default)]
+    pub webui_cert: String,
+    #[serde(default)]
+    pub webui_key: String,
 
     #[serde(default)]
     pub webui_toggle: bool,
+    /// Requests served at the same time
+    #[serde(default = "default_webui_threads")]
+    pub webui_threads: usize,
 
     pub grid_fee: f64,
     pub energy_tax: f64,
@@ -50,12 +97,181 @@ pub struct Config {
     pub cert_fee: f64,
     pub vat: f64,
 
+    /// Total power of the devices On may not exceed this, 0 = no limit
+    #[serde(default)]
+    pub max_power_kw: f64,
+
+    /// Minutes without valid prices before devices fall back to their failsafe
+    #[serde(default = "default_failsafe_grace")]
+    pub failsafe_grace: u64,
+
+    /// Devices with negative_on are forced On below this price
+    #[serde(default)]
+    pub negative_price: f64,
+    /// Compare the total price instead of spot against negative_price
+    #[serde(default)]
+    pub negative_total: bool,
+    /// Run and POSTed to at the start and end of each negative period
+    #[serde(default)]
+    pub negative_script_start: String,
+    #[serde(default)]
+    pub negative_script_end: String,
+    #[serde(default)]
+    pub negative_webhook: String,
+
+    /// Local time HH:MM when tomorrow's prices are usually published
+    #[serde(default = "default_tomorrow_publish")]
+    pub tomorrow_publish: String,
+    /// Minutes between attempts from tomorrow_publish until the prices arrive
+    #[serde(default = "default_tomorrow_poll")]
+    pub tomorrow_poll: u64,
+    /// Run and POSTed to when tomorrow's prices arrive
+    #[serde(default)]
+    pub tomorrow_script: String,
+    #[serde(default)]
+    pub tomorrow_webhook: String,
+
+    /// Notify once when the spot price rises above or falls below these
+    #[serde(default)]
+    pub notify_price_above: Option<f64>,
+    #[serde(default)]
+    pub notify_price_below: Option<f64>,
+    /// Hours a price download may fail before fetch_failing is sent
+    #[serde(default = "default_notify_fetch_hours")]
+    pub notify_fetch_hours: u64,
+    /// Where notifications are sent, `[[notifier]]` tables
+    #[serde(default)]
+    pub notifier: Vec<notify::Notifier>,
+
+    /// Cached price files, default is the system cache dir
+    #[serde(default)]
+    pub cache_dir: String,
+    /// Days to keep cached price files
+    #[serde(default = "default_cache_days")]
+    pub cache_days: u64,
+
+    /// History of prices and device actions, default is the system data dir
+    #[serde(default)]
+    pub data_dir: String,
+    /// Days of history to keep, 0 = keep everything
+    #[serde(default = "default_history_days")]
+    pub history_days: u64,
+
+    /// Log Telldus requests, scripts, webhooks and command notifiers instead of running them,
+    /// also set by --dry-run
+    #[serde(default)]
+    pub dry_run: bool,
+
     #[serde(default)]
     pub telldus_ip: String,
     #[serde(default)]
     pub telldus_token: String,
 }
 
+impl Config {
+    /// tomorrow_publish as a time of day, HH:MM
+    pub fn publish_time(&self) -> Result<Time, ConfigError> {
+        self.tomorrow_publish
+            .split_once(':')
+            .and_then(|(h, m)| {
+                Time::from_hms(h.trim().parse().ok()?, m.trim().parse().ok()?, 0).ok()
+            })
+            .ok_or_else(|| {
+                ConfigError::Invalid(format!(
+                    "tomorrow_publish {:?}, use HH:MM",
+                    self.tomorrow_publish
+                ))
+            })
+    }
+
+    /// The web UI's listen addresses, a bare IP gets webui_port
+    pub fn webui_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        self.webui_bind
+            .iter()
+            .map(|bind| {
+                bind.parse::<SocketAddr>()
+                    .or_else(|_| {
+                        bind.strip_prefix('[')
+                            .and_then(|b| b.strip_suffix(']'))
+                            .unwrap_or(bind)
+                            .parse::<IpAddr>()
+                            .map(|ip| SocketAddr::new(ip, self.webui_port))
+                    })
+                    .map_err(|_| ConfigError::Invalid(format!("webui_bind address {:?}", bind)))
+            })
+            .collect()
+    }
+
+    /// Checks what the types alone can't
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.webui_port == 0 {
+            return Err(ConfigError::Invalid("webui_port must be 1-65535".into()));
+        }
+        self.webui_addrs()?;
+        self.publish_time()?;
+        if self.webui_cert.is_empty() != self.webui_key.is_empty() {
+            return Err(ConfigError::Invalid(
+                "webui_cert and webui_key must be set together".into(),
+            ));
+        }
+        for notifier in &self.notifier {
+            if let notify::Sink::Smtp(smtp) = &notifier.sink {
+                if smtp.security == notify::Security::None && !smtp.username.is_empty() {
+                    return Err(ConfigError::Invalid(format!(
+                        "smtp notifier {} has a username with security = \"none\", \
+                         the password would be sent unencrypted",
+                        smtp.server
+                    )));
+                }
+            }
+            if let Some(kind) = notifier
+                .events
+                .iter()
+                .find(|e| !notify::KINDS.contains(&e.as_str()))
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "unknown notifier event {:?}, use one of {}",
+                    kind,
+                    notify::KINDS.join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_webui_bind() -> Vec<String> {
+    vec!["0.0.0.0".to_string()]
+}
+
+fn default_webui_threads() -> usize {
+    4
+}
+
+fn default_failsafe_grace() -> u64 {
+    60
+}
+
+fn default_tomorrow_publish() -> String {
+    "13:00".to_string()
+}
+
+fn default_tomorrow_poll() -> u64 {
+    5
+}
+
+fn default_notify_fetch_hours() -> u64 {
+    3
+}
+
+fn default_cache_days() -> u64 {
+    7
+}
+
+fn default_history_days() -> u64 {
+    365
+}
+
 /// Shared state for the webui
 #[derive(Clone, Serialize)]
 pub struct AppState {
@@ -63,4 +279,78 @@ pub struct AppState {
     pub devices: device_model::Devices,
     pub todays_spot_prices: Value,    // store the JSON array directly
     pub tomorrows_spot_prices: Value, // store the JSON array directly
+    pub stats: stats::Stats,
+    /// Devices are on their failsafe policy, there are no valid prices
+    pub fallback: bool,
+}
+
+/// Builders shared by the tests of other modules
+#[cfg(test)]
+pub mod tests {
+    use super::{Config, Slot};
+    use serde_json::Value;
+    use time::format_description::well_known::Rfc3339;
+    use time::{Date, Duration, Month, OffsetDateTime, Time, UtcOffset};
+
+    /// A config with only the required keys set
+    pub fn config() -> Config {
+        toml::from_str(
+            r#"
+            api = "https://example.com/api/{YEAR}/{MONTH}-{DAY}_{AREA}.json"
+            area = "SE3"
+            currency = "SEK_per_kWh"
+            interval = 60
+            webui_port = 8088
+            grid_fee = 0.0
+            energy_tax = 0.0
+            variable_costs = 0.0
+            spot_fee = 0.0
+            cert_fee = 0.0
+            vat = 0.0
+            "#,
+        )
+        .unwrap()
+    }
+
+    /// Midnight of the day the test prices are for
+    pub fn midnight() -> OffsetDateTime {
+        Date::from_calendar_date(2026, Month::March, 10)
+            .unwrap()
+            .with_time(Time::MIDNIGHT)
+            .assume_offset(UtcOffset::from_hms(1, 0, 0).unwrap())
+    }
+
+    /// Consecutive slots of `minutes` each from `start`, one per price
+    pub fn slots_from(start: OffsetDateTime, minutes: i64, prices: &[f64]) -> Vec<Slot> {
+        prices
+            .iter()
+            .enumerate()
+            .map(|(i, &price)| Slot {
+                start: start + Duration::minutes(minutes * i as i64),
+                end: start + Duration::minutes(minutes * (i as i64 + 1)),
+                price,
+            })
+            .collect()
+    }
+
+    /// Prices as served by the api for the slots, in SEK_per_kWh
+    pub fn json_of(slots: &[Slot]) -> Value {
+        Value::Array(
+            slots
+                .iter()
+                .map(|s| {
+                    serde_json::json!({
+                        "SEK_per_kWh": s.price,
+                        "time_start": s.start.format(&Rfc3339).unwrap(),
+                        "time_end": s.end.format(&Rfc3339).unwrap(),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Consecutive slots of `minutes` each from midnight
+    pub fn slots(minutes: i64, prices: &[f64]) -> Vec<Slot> {
+        slots_from(midnight(), minutes, prices)
+    }
 }
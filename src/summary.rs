@@ -0,0 +1,179 @@
+use serde::Serialize;
+use serde_json::Value;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{price, structs};
+
+/// The numbers the dashboard shows, computed once on the server
+#[derive(Serialize, Debug, Clone)]
+pub struct Summary {
+    pub currency: String,
+    pub current: Option<SlotPrice>,
+    pub next: Option<SlotPrice>,
+    /// Spot prices of today
+    pub today: Option<DayPrices>,
+    /// The cheapest run of `window_hours` from the current slot on, across midnight
+    pub cheapest_window: Option<Window>,
+    pub window_hours: u64,
+    pub tomorrow_available: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SlotPrice {
+    pub start: String,
+    pub end: String,
+    pub spot: f64,
+    /// Including fees and VAT
+    pub total: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DayPrices {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Window {
+    pub start: String,
+    pub end: String,
+    /// Average spot price over the window, weighted by slot length
+    pub spot: f64,
+    pub total: f64,
+}
+
+/// The longest cheapest window that can be asked for, today and tomorrow
+pub const MAX_WINDOW_HOURS: u64 = 48;
+
+/// Summarize today's and tomorrow's prices at the current time
+pub fn summary(
+    today: &Value,
+    tomorrow: &Value,
+    config: &structs::Config,
+    window_hours: u64,
+) -> Summary {
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    let window_hours = window_hours.clamp(1, MAX_WINDOW_HOURS);
+    let today_slots = price::slots(today, &config.currency);
+    let tomorrow_slots = price::slots(tomorrow, &config.currency);
+
+    // Slots from the current one on
+    let upcoming: Vec<structs::Slot> = today_slots
+        .iter()
+        .chain(&tomorrow_slots)
+        .filter(|slot| slot.end > now)
+        .cloned()
+        .collect();
+
+    let current = price::current_price(today, &config.currency).and_then(|_| {
+        upcoming
+            .first()
+            .filter(|slot| slot.start <= now)
+            .map(|slot| slot_price(slot, config))
+    });
+    let next = upcoming
+        .iter()
+        .find(|slot| slot.start > now)
+        .map(|slot| slot_price(slot, config));
+
+    let today_prices = price::average_price(today, &config.currency).map(|avg| DayPrices {
+        min: today_slots.iter().map(|s| s.price).fold(f64::MAX, f64::min),
+        max: today_slots.iter().map(|s| s.price).fold(f64::MIN, f64::max),
+        avg,
+    });
+
+    Summary {
+        currency: config.currency.clone(),
+        current,
+        next,
+        today: today_prices,
+        cheapest_window: cheapest_window(&upcoming, window_hours, config),
+        window_hours,
+        tomorrow_available: !tomorrow_slots.is_empty(),
+    }
+}
+
+fn slot_price(slot: &structs::Slot, config: &structs::Config) -> SlotPrice {
+    SlotPrice {
+        start: format(slot.start),
+        end: format(slot.end),
+        spot: slot.price,
+        total: price::total_price(slot.price, config),
+    }
+}
+
+/// The consecutive slots covering at least `hours` with the lowest average price
+fn cheapest_window(
+    slots: &[structs::Slot],
+    hours: u64,
+    config: &structs::Config,
+) -> Option<Window> {
+    let minutes = hours.clamp(1, MAX_WINDOW_HOURS).saturating_mul(60) as f64;
+    let mut best: Option<(f64, usize, usize)> = None;
+
+    for first in 0..slots.len() {
+        let mut covered = 0.0;
+        for last in first..slots.len() {
+            // Only contiguous slots make a window
+            if last > first && slots[last].start != slots[last - 1].end {
+                break;
+            }
+            covered += slots[last].minutes();
+            if covered >= minutes {
+                let average = price::average_of(&slots[first..=last])?;
+                if best.is_none_or(|(b, _, _)| average < b) {
+                    best = Some((average, first, last));
+                }
+                break;
+            }
+        }
+    }
+
+    best.map(|(average, first, last)| Window {
+        start: format(slots[first].start),
+        end: format(slots[last].end),
+        spot: average,
+        total: price::total_price(average, config),
+    })
+}
+
+fn format(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::tests::{config, slots};
+
+    #[test]
+    fn cheapest_window_finds_lowest_average_run() {
+        let slots = slots(60, &[5.0, 1.0, 4.0, 2.0, 2.0, 9.0]);
+        let window = cheapest_window(&slots, 2, &config()).unwrap();
+        assert_eq!(window.start, format(slots[3].start));
+        assert_eq!(window.end, format(slots[4].end));
+        assert_eq!(window.spot, 2.0);
+    }
+
+    #[test]
+    fn cheapest_window_counts_minutes_of_short_slots() {
+        let slots = slots(15, &[4.0, 4.0, 1.0, 1.0, 1.0, 1.0, 4.0, 4.0]);
+        let window = cheapest_window(&slots, 1, &config()).unwrap();
+        assert_eq!(window.start, format(slots[2].start));
+        assert_eq!(window.spot, 1.0);
+    }
+
+    #[test]
+    fn cheapest_window_needs_enough_slots() {
+        let slots = slots(60, &[1.0, 2.0]);
+        assert!(cheapest_window(&slots, 3, &config()).is_none());
+    }
+
+    #[test]
+    fn cheapest_window_clamps_huge_windows() {
+        let slots = slots(60, &[1.0; 48]);
+        let window = cheapest_window(&slots, u64::MAX, &config()).unwrap();
+        assert_eq!(window.end, format(slots[47].end));
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Load the PEM certificate chain and private key for the web UI
+pub fn server_config(cert: &str, key: &str) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Could not read certificate {}: {e}", cert))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| anyhow!("Could not read private key {}: {e}", key))?;
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Connections relayed at once, beyond this new ones are dropped
+const MAX_CONNECTIONS: usize = 64;
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// A client gets this long to finish the handshake and send its first request, and the
+/// backend this long between answers, so an idle connection can't hold a slot forever. After
+/// the first request the client may stay quiet, e.g. while it listens to /events, which the
+/// backend keeps alive.
+const IDLE: Duration = Duration::from_secs(60);
+
+/// Counts a relayed connection in ACTIVE until it's dropped
+struct Slot;
+
+impl Slot {
+    fn take() -> Option<Slot> {
+        if ACTIVE.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            ACTIVE.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accept TLS connections and relay each, decrypted, to the plain server at `backend`
+pub fn serve(listener: TcpListener, config: Arc<ServerConfig>, backend: SocketAddr) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("TLS accept failed: {e}");
+                continue;
+            }
+        };
+        let Some(slot) = Slot::take() else {
+            warn!("Too many TLS connections, dropping one");
+            continue;
+        };
+        let config = config.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = relay(stream, config, backend) {
+                debug!("TLS connection closed: {e}");
+            }
+        });
+    }
+}
+
+/// Relay one connection, client to backend on this thread and backend to client on another.
+/// Both block on their socket, whichever side ends shuts down both sockets so the other
+/// returns too.
+fn relay(socket: TcpStream, config: Arc<ServerConfig>, backend: SocketAddr) -> io::Result<()> {
+    socket.set_read_timeout(Some(IDLE))?;
+    let conn = Arc::new(Mutex::new(
+        ServerConnection::new(config).map_err(io::Error::other)?,
+    ));
+    let server = TcpStream::connect(backend)?;
+    server.set_read_timeout(Some(IDLE))?;
+
+    let responses = {
+        let (conn, socket, server) = (conn.clone(), socket.try_clone()?, server.try_clone()?);
+        thread::spawn(move || {
+            let result = to_client(&conn, &socket, &server);
+            close(&socket, &server);
+            result
+        })
+    };
+
+    let result = to_backend(&conn, &socket, &server);
+    close(&socket, &server);
+    let _ = responses.join();
+    result
+}
+
+/// Decrypt what the client sends and pass it on to the backend
+fn to_backend(
+    conn: &Mutex<ServerConnection>,
+    mut socket: &TcpStream,
+    mut server: &TcpStream,
+) -> io::Result<()> {
+    let mut raw = [0u8; 16 * 1024];
+    let mut plain = Vec::new();
+    loop {
+        let n = socket.read(&mut raw)?;
+        let mut received = &raw[..n];
+        let mut closed = n == 0;
+
+        {
+            let mut conn = lock(conn)?;
+            loop {
+                if !received.is_empty() {
+                    conn.read_tls(&mut received)?;
+                }
+                if let Err(e) = conn.process_new_packets() {
+                    // Tell the client why, if we can
+                    let _ = conn.write_tls(&mut socket);
+                    return Err(io::Error::other(e));
+                }
+                closed |= drain(&mut conn, &mut plain)?;
+                if received.is_empty() {
+                    break;
+                }
+            }
+            // Handshake messages and alerts
+            while conn.wants_write() {
+                conn.write_tls(&mut socket)?;
+            }
+        }
+
+        if !plain.is_empty() {
+            server.write_all(&plain)?;
+            plain.clear();
+            // From here on the backend's silence decides when the connection is idle
+            socket.set_read_timeout(None)?;
+        }
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+/// Read the decrypted data into `plain`, true once the client has closed the connection
+fn drain(conn: &mut ServerConnection, plain: &mut Vec<u8>) -> io::Result<bool> {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        match conn.reader().read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(n) => plain.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(true),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Encrypt what the backend answers and send it to the client
+fn to_client(
+    conn: &Mutex<ServerConnection>,
+    mut socket: &TcpStream,
+    mut server: &TcpStream,
+) -> io::Result<()> {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let n = server.read(&mut buf)?;
+        let mut conn = lock(conn)?;
+        if n == 0 {
+            conn.send_close_notify();
+        } else {
+            conn.writer().write_all(&buf[..n])?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut socket)?;
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn lock(conn: &Mutex<ServerConnection>) -> io::Result<MutexGuard<'_, ServerConnection>> {
+    conn.lock()
+        .map_err(|_| io::Error::other("TLS connection lock poisoned"))
+}
+
+fn close(socket: &TcpStream, server: &TcpStream) {
+    let _ = socket.shutdown(Shutdown::Both);
+    let _ = server.shutdown(Shutdown::Both);
+}
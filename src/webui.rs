@@ -1,39 +1,147 @@
 use log::{debug, error, info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::Write;
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::{
     fs,
     sync::{Arc, Mutex},
+    thread,
+    time::Duration as TimeDuration,
 };
+use time::format_description::well_known::Iso8601;
+use time::{Date, Duration, OffsetDateTime};
 use tiny_http::StatusCode;
 use tiny_http::{Response, Server};
 use urlencoding::decode;
 
-use crate::telldus;
-use crate::{device_model, structs};
+use crate::{api, device_model, structs};
+use crate::{events, history, metrics, plan, price, summary, telldus, tls};
 
-fn respond_json(request: tiny_http::Request, body: String, status: StatusCode) {
+fn respond_text(request: tiny_http::Request, body: &str, status: StatusCode, content_type: &str) {
     let _ = request.respond(
         Response::from_string(body)
             .with_status_code(status)
             .with_header(
-                "Content-Type: application/json"
+                format!("Content-Type: {}", content_type)
                     .parse::<tiny_http::Header>()
                     .unwrap(),
             ),
     );
 }
 
-fn respond_text(request: tiny_http::Request, body: &str, status: StatusCode, content_type: &str) {
-    let _ = request.respond(
-        Response::from_string(body)
-            .with_status_code(status)
-            .with_header(
-                format!("Content-Type: {}", content_type)
-                    .parse::<tiny_http::Header>()
-                    .unwrap(),
-            ),
+fn respond_json<T: Serialize>(request: tiny_http::Request, body: &T, status: StatusCode) {
+    match serde_json::to_string(body) {
+        Ok(json) => respond_text(request, &json, status, "application/json"),
+        Err(e) => {
+            error!("JSON serialize failed: {e}");
+            respond_error(request, 500, "internal_error", "JSON serialize failed");
+        }
+    }
+}
+
+fn respond_error(
+    request: tiny_http::Request,
+    code: u16,
+    status: &'static str,
+    error: impl Into<String>,
+) {
+    let body = api::ApiError {
+        status,
+        error: error.into(),
+    };
+    respond_text(
+        request,
+        &serde_json::to_string(&body).unwrap_or_default(),
+        StatusCode(code),
+        "application/json",
     );
 }
 
+/// Serialize part of the shared state
+fn respond_state(
+    request: tiny_http::Request,
+    data: &Arc<Mutex<structs::AppState>>,
+    serialize: impl FnOnce(&structs::AppState) -> serde_json::Result<String>,
+) {
+    let result = match data.lock() {
+        Ok(state) => serialize(&state),
+        Err(e) => {
+            error!("State mutex poisoned: {e}");
+            respond_error(request, 500, "internal_error", "State unavailable");
+            return;
+        }
+    };
+
+    match result {
+        Ok(json) => respond_text(request, &json, StatusCode(200), "application/json"),
+        Err(e) => {
+            error!("JSON serialize failed: {e}");
+            respond_error(request, 500, "internal_error", "JSON serialize failed");
+        }
+    }
+}
+
+/// Parse the query string into its request struct
+fn parse_query<T: DeserializeOwned + Default>(query: &str) -> Result<T, String> {
+    if query.is_empty() {
+        return Ok(T::default());
+    }
+    serde_urlencoded::from_str(query).map_err(|e| format!("Invalid query: {e}"))
+}
+
+/// The longest range /prices and /actions answer, when history_days keeps everything
+const MAX_RANGE_DAYS: u64 = 366;
+
+/// Days /prices and /actions answer at once, no more than is kept
+fn range_days(config: &structs::Config) -> u64 {
+    match config.history_days {
+        0 => MAX_RANGE_DAYS,
+        days => days,
+    }
+}
+
+fn today() -> Date {
+    OffsetDateTime::now_local()
+        .unwrap_or_else(|_| OffsetDateTime::now_utc())
+        .date()
+}
+
+/// The from and to dates of a query, the last 30 days by default, at most `max_days` days
+fn date_range(
+    from: Option<String>,
+    to: Option<String>,
+    today: Date,
+    max_days: u64,
+) -> Result<(Date, Date), String> {
+    let date = |value: Option<String>, default: Date| match value {
+        Some(value) => Date::parse(&value, &Iso8601::DATE)
+            .map_err(|_| format!("Invalid date {}, expected YYYY-MM-DD", value)),
+        None => Ok(default),
+    };
+    let to = date(to, today)?;
+    let from = date(from, to - Duration::days(30))?;
+
+    if from > to {
+        return Err(format!("from {} is after to {}", from, to));
+    }
+    if (to - from).whole_days() as u64 >= max_days {
+        return Err(format!("At most {} days at a time", max_days));
+    }
+    Ok((from, to))
+}
+
+/// Show a manual switch in /devices right away, before the next control round
+fn set_state(data: &Arc<Mutex<structs::AppState>>, name: &str, state: &device_model::State) {
+    if let Ok(mut app) = data.lock() {
+        for device in app.devices.device.iter_mut().filter(|d| d.name == name) {
+            device.state = state.clone();
+        }
+    }
+}
+
 fn read_static(path: &str, embedded: &str, debug: bool) -> Result<String, std::io::Error> {
     if debug {
         fs::read_to_string(path)
@@ -42,277 +150,665 @@ fn read_static(path: &str, embedded: &str, debug: bool) -> Result<String, std::i
     }
 }
 
-pub fn run_server(
-    data: Arc<Mutex<structs::AppState>>,
-    config: &structs::Config,
-    mut devices: device_model::Devices,
+fn respond_static(
+    request: tiny_http::Request,
+    path: &str,
+    embedded: &str,
+    debug: bool,
+    content_type: &str,
 ) {
-    #[cfg(debug_assertions)]
-    const DEBUG: bool = true;
-    #[cfg(not(debug_assertions))]
-    const DEBUG: bool = false;
-
-    let addr = format!("0.0.0.0:{}", config.webui_port);
-    let server = Server::http(&addr).expect("Failed to bind HTTP server");
-
-    for request in server.incoming_requests() {
-        let url = request.url().to_string();
-        debug!("Incoming request: {}", url);
-
-        match (request.method(), url.as_str()) {
-            // ---------------- health ----------------
-            (_, "/health") => {
-                respond_text(request, "OK", StatusCode(200), "text/plain");
-            }
+    match read_static(path, embedded, debug) {
+        Ok(body) => respond_text(request, &body, StatusCode(200), content_type),
+        Err(e) => {
+            error!("Failed to load {}: {e}", path);
+            respond_text(request, "Internal error", StatusCode(500), "text/plain");
+        }
+    }
+}
 
-            // ---------------- listdevices ----------------
-            (_, "/listdevices") => {
-                let (json, status) = match telldus::telldus_list(config) {
-                    Ok(json) => (json, StatusCode(200)),
-                    Err(e) => {
-                        error!("Telldus request failed: {e}. Check telldus ip address and token.");
-                        (r#"{ "device": [] }"#.to_string(), StatusCode(500))
-                    }
-                };
+/// Comment line sent to idle event clients, so dead connections are noticed
+const KEEPALIVE: TimeDuration = TimeDuration::from_secs(15);
 
-                respond_json(request, json, status);
-            }
+/// Event streams open at once, beyond this new clients get 503 and fall back to polling
+static EVENT_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+const MAX_EVENT_CLIENTS: usize = 16;
 
-            // ---------------- shared state helpers ----------------
-            (_, "/data") => {
-                let state = match data.lock() {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("State mutex poisoned: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                        continue;
-                    }
-                };
+/// Stream Server-Sent Events to one client on its own thread until it disconnects
+fn serve_events(request: tiny_http::Request) {
+    if EVENT_CLIENTS.fetch_add(1, Ordering::SeqCst) >= MAX_EVENT_CLIENTS {
+        EVENT_CLIENTS.fetch_sub(1, Ordering::SeqCst);
+        respond_error(request, 503, "busy", "Too many event clients, try again");
+        return;
+    }
 
-                match serde_json::to_string(&*state) {
-                    Ok(json) => respond_json(request, json, StatusCode(200)),
-                    Err(e) => {
-                        error!("JSON serialize failed: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                    }
-                }
-            }
+    let events = events::subscribe();
+    thread::spawn(move || {
+        stream_events(request, events);
+        EVENT_CLIENTS.fetch_sub(1, Ordering::SeqCst);
+    });
+}
 
-            (_, "/config") => {
-                let state = match data.lock() {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("State mutex poisoned: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                        continue;
-                    }
-                };
+fn stream_events(request: tiny_http::Request, events: mpsc::Receiver<String>) {
+    let mut writer = request.into_writer();
+    let head = "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/event-stream\r\n\
+                Cache-Control: no-cache\r\n\
+                Connection: keep-alive\r\n\r\n";
+    let mut message = head.to_string();
+
+    loop {
+        if writer
+            .write_all(message.as_bytes())
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            debug!("Event client disconnected");
+            return;
+        }
 
-                match serde_json::to_string(&state.config) {
-                    Ok(json) => respond_json(request, json, StatusCode(200)),
-                    Err(e) => {
-                        error!("JSON serialize failed: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                    }
-                }
-            }
+        message = match events.recv_timeout(KEEPALIVE) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+            // Fell behind, the client reconnects and refetches
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+    }
+}
 
-            (_, "/devices") => {
-                let state = match data.lock() {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("State mutex poisoned: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                        continue;
-                    }
-                };
+fn health(data: &Arc<Mutex<structs::AppState>>) -> api::Health {
+    let fetch = price::fetch_status();
+    let today = OffsetDateTime::now_local()
+        .unwrap_or_else(|_| OffsetDateTime::now_utc())
+        .date()
+        .to_string();
+
+    api::Health {
+        status: if fetch.failing.contains_key(&today) {
+            "degraded"
+        } else {
+            "ok"
+        },
+        fallback: data.lock().map(|s| s.fallback).unwrap_or(false),
+        fetch,
+    }
+}
 
-                match serde_json::to_string(&state.devices) {
-                    Ok(json) => respond_json(request, json, StatusCode(200)),
-                    Err(e) => {
-                        error!("JSON serialize failed: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                    }
-                }
-            }
+/// Devices known to the Telldus gateway
+fn list_telldus(request: tiny_http::Request, config: &structs::Config) {
+    let telldus_config = config.clone();
+    let result =
+        with_timeout(move || telldus::telldus_list(&telldus_config).map_err(|e| e.to_string()));
+    match result {
+        Some(Ok(json)) => match serde_json::from_str::<serde_json::Value>(&json) {
+            Ok(list) => respond_json(request, &list, StatusCode(200)),
+            Err(e) => respond_error(request, 502, "telldus_error", e.to_string()),
+        },
+        Some(Err(e)) => {
+            error!("Telldus request failed: {e}. Check telldus ip address and token.");
+            respond_error(request, 502, "telldus_error", e);
+        }
+        None => respond_error(request, 504, "timeout", "Telldus did not answer"),
+    }
+}
 
-            (_, "/today") => {
-                let state = match data.lock() {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("State mutex poisoned: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                        continue;
-                    }
-                };
+/// Manually switch a device from the web UI
+fn switch(request: tiny_http::Request, ctx: &Arc<Context>, name_encoded: &str, on: bool) {
+    let (config, devices) = (&ctx.config, &ctx.devices);
+    let action = if on { "on" } else { "off" };
+
+    if !config.webui_toggle {
+        warn!("Disabled: User switching {} device", action);
+        respond_error(
+            request,
+            403,
+            "forbidden",
+            "Switching is disabled, see webui_toggle",
+        );
+        return;
+    }
 
-                match serde_json::to_string(&state.todays_spot_prices) {
-                    Ok(json) => respond_json(request, json, StatusCode(200)),
-                    Err(e) => {
-                        error!("JSON serialize failed: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                    }
+    let name = decode(name_encoded)
+        .map(|n| n.into_owned())
+        .unwrap_or_else(|_| name_encoded.to_string());
+
+    // Don't hold the lock while switching, other workers may switch other devices
+    let device = devices
+        .lock()
+        .ok()
+        .and_then(|d| d.device.iter().find(|d| d.name == name).cloned());
+    let Some(device) = device else {
+        respond_error(
+            request,
+            404,
+            "not_found",
+            format!("No device named {}", name),
+        );
+        return;
+    };
+
+    info!("User switching {} device {}", action, name);
+    // The switch is recorded when it finishes, also if we've given up waiting and answered 504,
+    // so the state and history match what the device actually did
+    let switch_ctx = ctx.clone();
+    let switch_name = name.clone();
+    let result = with_timeout(move || {
+        let ctx = switch_ctx;
+        let previous = device.state.clone();
+        let result = if on {
+            device.switch_on(&ctx.config)
+        } else {
+            device.switch_off(&ctx.config)
+        };
+        if let Ok(state) = &result {
+            history::record_action(&switch_name, &previous, state, "Web UI", None);
+            set_state(&ctx.data, &switch_name, state);
+            if let Ok(mut devices) = ctx.devices.lock() {
+                for device in devices.device.iter_mut().filter(|d| d.name == switch_name) {
+                    device.state = state.clone();
                 }
             }
+            events::flush();
+        }
+        result
+    });
+
+    match result {
+        Some(Ok(_)) => {
+            let body = api::SwitchResult {
+                status: "ok",
+                action,
+                name,
+            };
+            respond_json(request, &body, StatusCode(200));
+        }
+        Some(Err(e)) => {
+            error!("Failed to switch {} {}: {e}", action, name);
+            respond_error(request, 502, "switch_failed", e.to_string());
+        }
+        None => {
+            error!("Switching {} {} timed out", action, name);
+            respond_error(
+                request,
+                504,
+                "timeout",
+                format!("Switching {} timed out", name),
+            );
+        }
+    }
+}
 
-            (_, "/tomorrow") => {
-                let state = match data.lock() {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("State mutex poisoned: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                        continue;
-                    }
-                };
+/// Give up waiting for a slow call, e.g. to Telldus, and answer 504
+#[cfg(not(test))]
+const REQUEST_TIMEOUT: TimeDuration = TimeDuration::from_secs(10);
+#[cfg(test)]
+const REQUEST_TIMEOUT: TimeDuration = TimeDuration::from_millis(500);
 
-                match serde_json::to_string(&state.tomorrows_spot_prices) {
-                    Ok(json) => respond_json(request, json, StatusCode(200)),
-                    Err(e) => {
-                        error!("JSON serialize failed: {e}");
-                        respond_json(request, "{}".to_string(), StatusCode(500));
-                    }
+#[cfg(debug_assertions)]
+const DEBUG: bool = true;
+#[cfg(not(debug_assertions))]
+const DEBUG: bool = false;
+
+/// Shared by the request workers
+struct Context {
+    data: Arc<Mutex<structs::AppState>>,
+    config: structs::Config,
+    /// The web UI's own copy of the devices, for manual switching
+    devices: Mutex<device_model::Devices>,
+}
+
+/// Run a slow call on its own thread, None if it takes longer than REQUEST_TIMEOUT
+fn with_timeout<T: Send + 'static>(call: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(call());
+    });
+    rx.recv_timeout(REQUEST_TIMEOUT).ok()
+}
+
+/// Slow requests in their own threads, beyond MAX_SLOW they get 503 instead of piling up
+static SLOW: AtomicUsize = AtomicUsize::new(0);
+const MAX_SLOW: usize = 8;
+
+/// Answer a request that calls out to Telldus or scripts on its own thread, so the workers stay
+/// free for /health and the other quick endpoints
+fn offload(
+    request: tiny_http::Request,
+    ctx: &Arc<Context>,
+    job: impl FnOnce(tiny_http::Request, &Arc<Context>) + Send + 'static,
+) {
+    if SLOW.fetch_add(1, Ordering::SeqCst) >= MAX_SLOW {
+        SLOW.fetch_sub(1, Ordering::SeqCst);
+        respond_error(request, 503, "busy", "Too many slow requests, try again");
+        return;
+    }
+
+    let ctx = ctx.clone();
+    thread::spawn(move || {
+        job(request, &ctx);
+        SLOW.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Serve the web UI on every webui_bind address, each on webui_threads workers, so a slow
+/// request doesn't hold up the others. With TLS the connections are decrypted and relayed to
+/// a plain server on localhost.
+pub fn run_server(
+    data: Arc<Mutex<structs::AppState>>,
+    config: &structs::Config,
+    devices: device_model::Devices,
+) {
+    // Checked when the config was loaded
+    let addrs = config.webui_addrs().unwrap_or_default();
+    if addrs.is_empty() {
+        info!("The web UI is disabled, webui_bind is empty");
+        return;
+    }
+
+    let mut servers = vec![];
+    if config.webui_cert.is_empty() {
+        for addr in addrs {
+            match Server::http(addr) {
+                Ok(server) => {
+                    info!("Starting the web UI on http://{}", addr);
+                    servers.push(server);
                 }
+                Err(e) => error!("Could not start the web UI on {}: {e}", addr),
             }
-
-            (tiny_http::Method::Post, path) if path.starts_with("/switchon/") => {
-                if !config.webui_toggle {
-                    warn!("Disabled: User switching On device");
-                    respond_json(
-                        request,
-                        r#"{"status":"forbidden"}"#.to_string(),
-                        StatusCode(403),
-                    );
-                    continue;
+        }
+    } else {
+        let tls = match tls::server_config(&config.webui_cert, &config.webui_key) {
+            Ok(tls) => tls,
+            Err(e) => {
+                error!("Could not start the web UI with TLS: {e}");
+                return;
+            }
+        };
+        let server = match Server::http("127.0.0.1:0") {
+            Ok(server) => server,
+            Err(e) => {
+                error!("Could not start the web UI: {e}");
+                return;
+            }
+        };
+        let Some(backend) = server.server_addr().to_ip() else {
+            return;
+        };
+
+        for addr in addrs {
+            match TcpListener::bind(addr) {
+                Ok(listener) => {
+                    info!("Starting the web UI on https://{}", addr);
+                    let tls = tls.clone();
+                    thread::spawn(move || tls::serve(listener, tls, backend));
                 }
+                Err(e) => error!("Could not start the web UI on {}: {e}", addr),
+            }
+        }
+        servers.push(server);
+    }
+
+    let context = Arc::new(Context {
+        data,
+        config: config.clone(),
+        devices: Mutex::new(devices),
+    });
+
+    let workers: Vec<_> = servers
+        .into_iter()
+        .flat_map(|server| spawn_workers(server, config.webui_threads, &context))
+        .collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
 
-                let name_encoded = path.trim_start_matches("/switchon/");
-                let name = decode(name_encoded).unwrap_or_else(|_| name_encoded.to_string().into());
-
-                let mut found = false;
-                for device in devices.device.iter_mut() {
-                    if device.name == name {
-                        info!("User switching On device {}", name);
-                        if let Err(e) = device.switch_on(config) {
-                            error!("Failed to switch on {}: {e}", name);
-                        }
-                        found = true;
+/// At least one worker thread answering the server's requests
+fn spawn_workers(
+    server: Server,
+    threads: usize,
+    context: &Arc<Context>,
+) -> Vec<thread::JoinHandle<()>> {
+    let server = Arc::new(server);
+    (0..threads.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let context = context.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    // A panicking handler fails its request, not the worker
+                    let result =
+                        panic::catch_unwind(AssertUnwindSafe(|| handle(request, &context)));
+                    if result.is_err() {
+                        error!("Web UI request handler panicked");
                     }
                 }
+            })
+        })
+        .collect()
+}
 
-                if found {
-                    respond_json(
-                        request,
-                        format!(r#"{{"status":"ok","action":"on","name":"{}"}}"#, name),
-                        StatusCode(200),
-                    );
-                } else {
-                    respond_json(
-                        request,
-                        format!(
-                            r#"{{"status":"not_found","action":"on","name":"{}"}}"#,
-                            name
-                        ),
-                        StatusCode(404),
-                    );
-                }
+fn handle(request: tiny_http::Request, ctx: &Arc<Context>) {
+    let data = &ctx.data;
+    let config = &ctx.config;
+
+    let url = request.url().to_string();
+    let method = request.method().as_str().to_string();
+    debug!("Incoming request: {} {}", method, url);
+
+    let (path, query) = url.split_once('?').unwrap_or((url.as_str(), ""));
+
+    // The versioned API and the unversioned aliases the bundled UI uses
+    let (path, versioned) = match path.strip_prefix(api::PREFIX) {
+        Some(path) => (path, true),
+        None => (path, false),
+    };
+
+    let routes: Vec<&api::Route> = api::ROUTES
+        .iter()
+        .filter(|r| (r.api || !versioned) && r.matches(path))
+        .collect();
+    if routes.is_empty() {
+        respond_error(request, 404, "not_found", format!("No endpoint {}", path));
+        return;
+    }
+    if !routes.iter().any(|r| r.allows(&method)) {
+        let allow: Vec<&str> = routes.iter().map(|r| r.method).collect();
+        let body = api::ApiError {
+            status: "method_not_allowed",
+            error: format!("{} is not allowed, use {}", method, allow.join(", ")),
+        };
+        let _ = request.respond(
+            Response::from_string(serde_json::to_string(&body).unwrap_or_default())
+                .with_status_code(StatusCode(405))
+                .with_header(
+                    "Content-Type: application/json"
+                        .parse::<tiny_http::Header>()
+                        .unwrap(),
+                )
+                .with_header(
+                    format!("Allow: {}", allow.join(", "))
+                        .parse::<tiny_http::Header>()
+                        .unwrap(),
+                ),
+        );
+        return;
+    }
+
+    match path {
+        // ---------------- health ----------------
+        "/health" => respond_json(request, &health(data), StatusCode(200)),
+
+        // ---------------- live events ----------------
+        "/events" => serve_events(request),
+
+        // ---------------- listdevices ----------------
+        "/listdevices" => offload(request, ctx, |request, ctx| {
+            list_telldus(request, &ctx.config)
+        }),
+
+        // ---------------- shared state helpers ----------------
+        "/data" => respond_state(request, data, serde_json::to_string),
+        "/config" => respond_state(request, data, |s| serde_json::to_string(&s.config)),
+        "/devices" => respond_state(request, data, |s| serde_json::to_string(&s.devices)),
+        "/stats" => respond_state(request, data, |s| serde_json::to_string(&s.stats)),
+        "/today" => respond_state(request, data, |s| {
+            serde_json::to_string(&s.todays_spot_prices)
+        }),
+        "/tomorrow" => respond_state(request, data, |s| {
+            serde_json::to_string(&s.tomorrows_spot_prices)
+        }),
+
+        // Computed on a copy of the prices, the lock is only held to take it
+        "/summary" => match parse_query::<api::SummaryQuery>(query) {
+            Ok(q) => {
+                let Ok((today, tomorrow, config)) = data.lock().map(|s| {
+                    (
+                        s.todays_spot_prices.clone(),
+                        s.tomorrows_spot_prices.clone(),
+                        s.config.clone(),
+                    )
+                }) else {
+                    respond_error(request, 500, "internal_error", "State unavailable");
+                    return;
+                };
+                let summary = summary::summary(&today, &tomorrow, &config, q.window.unwrap_or(1));
+                respond_json(request, &summary, StatusCode(200));
             }
+            Err(e) => respond_error(request, 400, "bad_request", e),
+        },
+
+        // Planned on a copy, the control loop isn't kept waiting on the lock
+        "/plan" => {
+            let Ok(state) = data.lock().map(|s| s.clone()) else {
+                respond_error(request, 500, "internal_error", "State unavailable");
+                return;
+            };
+            let plan = plan::plan(
+                &state.todays_spot_prices,
+                &state.tomorrows_spot_prices,
+                &state.devices,
+                &state.config,
+            );
+            respond_json(request, &plan, StatusCode(200));
+        }
 
-            (tiny_http::Method::Post, path) if path.starts_with("/switchoff/") => {
-                if !config.webui_toggle {
-                    warn!("Disabled: User switching Off device");
-                    respond_json(
-                        request,
-                        r#"{"status":"forbidden"}"#.to_string(),
-                        StatusCode(403),
-                    );
-                    continue;
-                }
+        "/metrics" => {
+            let Ok(state) = data.lock().map(|s| s.clone()) else {
+                respond_error(request, 500, "internal_error", "State unavailable");
+                return;
+            };
+            let body = metrics::render(&state);
+            respond_text(request, &body, StatusCode(200), api::METRICS_TYPE);
+        }
 
-                let name_encoded = path.trim_start_matches("/switchoff/");
-                let name = decode(name_encoded).unwrap_or_else(|_| name_encoded.to_string().into());
-
-                let mut found = false;
-                for device in devices.device.iter_mut() {
-                    if device.name == name {
-                        info!("User switching Off device {}", name);
-                        if let Err(e) = device.switch_off(config) {
-                            error!("Failed to switch off {}: {e}", name);
-                        }
-                        found = true;
-                    }
+        // ---------------- history ----------------
+        "/prices" => {
+            let q = match parse_query::<api::PricesQuery>(query) {
+                Ok(q) => q,
+                Err(e) => {
+                    respond_error(request, 400, "bad_request", e);
+                    return;
                 }
+            };
 
-                if found {
-                    respond_json(
-                        request,
-                        format!(r#"{{"status":"ok","action":"off","name":"{}"}}"#, name),
-                        StatusCode(200),
-                    );
-                } else {
-                    respond_json(
-                        request,
-                        format!(
-                            r#"{{"status":"not_found","action":"off","name":"{}"}}"#,
-                            name
-                        ),
-                        StatusCode(404),
-                    );
-                }
-            }
-            // ---------------- static files ----------------
-            (_, "/pricecontrol.js") => {
-                match read_static(
-                    "static/pricecontrol.js",
-                    include_str!("../static/pricecontrol.js"),
-                    DEBUG,
-                ) {
-                    Ok(js) => respond_text(request, &js, StatusCode(200), "application/javascript"),
-                    Err(e) => {
-                        error!("Failed to load pricecontrol.js: {e}");
-                        respond_text(request, "Internal error", StatusCode(500), "text/plain");
-                    }
+            match date_range(q.from, q.to, today(), range_days(config)) {
+                Ok((from, to)) => {
+                    let area = q.area.unwrap_or(config.area.clone());
+                    let days = history::summaries(from, to, &area, &config.currency);
+                    respond_json(request, &days, StatusCode(200));
                 }
+                Err(e) => respond_error(request, 400, "bad_request", e),
             }
+        }
 
-            (_, "/listdevices.htm") => {
-                match read_static(
-                    "static/listdevices.htm",
-                    include_str!("../static/listdevices.htm"),
-                    DEBUG,
-                ) {
-                    Ok(html) => respond_text(request, &html, StatusCode(200), "text/html"),
-                    Err(e) => {
-                        error!("Failed to load listdevices.htm: {e}");
-                        respond_text(request, "Internal error", StatusCode(500), "text/plain");
-                    }
+        "/actions" => {
+            let q = match parse_query::<api::ActionsQuery>(query) {
+                Ok(q) => q,
+                Err(e) => {
+                    respond_error(request, 400, "bad_request", e);
+                    return;
                 }
-            }
+            };
 
-            (_, "/") => {
-                match read_static(
-                    "static/index.html",
-                    include_str!("../static/index.html"),
-                    DEBUG,
-                ) {
-                    Ok(raw) => {
-                        let html = raw
-                            .replace("{{PROJECT_NAME}}", env!("CARGO_PKG_NAME"))
-                            .replace("{{PROJECT_VERSION}}", env!("CARGO_PKG_VERSION"))
-                            .replace("{{PROJECT_AUTHORS}}", env!("CARGO_PKG_AUTHORS"));
-
-                        respond_text(request, &html, StatusCode(200), "text/html");
-                    }
-                    Err(e) => {
-                        error!("Failed to load index.html: {e}");
-                        respond_text(request, "Internal error", StatusCode(500), "text/plain");
-                    }
+            match date_range(q.from, q.to, today(), range_days(config)) {
+                Ok((from, to)) => {
+                    respond_json(request, &history::actions(from, to), StatusCode(200))
                 }
+                Err(e) => respond_error(request, 400, "bad_request", e),
             }
+        }
 
-            // ---------------- fallback ----------------
-            _ => {
-                respond_text(request, "Not found", StatusCode(404), "text/plain");
+        // ---------------- switching ----------------
+        path if path.starts_with("/switchon/") => {
+            let name = path.trim_start_matches("/switchon/").to_string();
+            offload(request, ctx, move |request, ctx| {
+                switch(request, ctx, &name, true)
+            });
+        }
+
+        path if path.starts_with("/switchoff/") => {
+            let name = path.trim_start_matches("/switchoff/").to_string();
+            offload(request, ctx, move |request, ctx| {
+                switch(request, ctx, &name, false)
+            });
+        }
+
+        "/openapi.json" => respond_json(request, &api::openapi(), StatusCode(200)),
+
+        // ---------------- static files ----------------
+        "/pricecontrol.js" => respond_static(
+            request,
+            "static/pricecontrol.js",
+            include_str!("../static/pricecontrol.js"),
+            DEBUG,
+            "application/javascript",
+        ),
+
+        "/chart.js" => respond_static(
+            request,
+            "static/chart.js",
+            include_str!("../static/chart.js"),
+            DEBUG,
+            "application/javascript",
+        ),
+
+        "/listdevices.htm" => respond_static(
+            request,
+            "static/listdevices.htm",
+            include_str!("../static/listdevices.htm"),
+            DEBUG,
+            "text/html",
+        ),
+
+        "/" => {
+            match read_static(
+                "static/index.html",
+                include_str!("../static/index.html"),
+                DEBUG,
+            ) {
+                Ok(raw) => {
+                    let html = raw
+                        .replace("{{PROJECT_NAME}}", env!("CARGO_PKG_NAME"))
+                        .replace("{{PROJECT_VERSION}}", env!("CARGO_PKG_VERSION"))
+                        .replace("{{PROJECT_AUTHORS}}", env!("CARGO_PKG_AUTHORS"));
+
+                    respond_text(request, &html, StatusCode(200), "text/html");
+                }
+                Err(e) => {
+                    error!("Failed to load index.html: {e}");
+                    respond_text(request, "Internal error", StatusCode(500), "text/plain");
+                }
             }
         }
+
+        // ---------------- fallback ----------------
+        _ => respond_error(request, 404, "not_found", format!("No endpoint {}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::tests::config;
+    use std::time::Instant;
+    use time::Month;
+
+    fn date(day: u8) -> Date {
+        Date::from_calendar_date(2026, Month::October, day).unwrap()
+    }
+
+    #[test]
+    fn date_range_defaults_to_the_last_30_days() {
+        let today = date(31);
+        assert_eq!(date_range(None, None, today, 365), Ok((date(1), today)));
+        assert_eq!(
+            date_range(None, Some("2026-10-17".into()), today, 365),
+            Ok((
+                Date::from_calendar_date(2026, Month::September, 17).unwrap(),
+                date(17)
+            ))
+        );
+        assert_eq!(
+            date_range(
+                Some("2026-10-05".into()),
+                Some("2026-10-05".into()),
+                today,
+                1
+            ),
+            Ok((date(5), date(5)))
+        );
+    }
+
+    #[test]
+    fn date_range_rejects_bad_and_long_ranges() {
+        let today = date(31);
+        let range =
+            |from: &str, to: &str, max| date_range(Some(from.into()), Some(to.into()), today, max);
+
+        assert!(range("2026-10-17", "2026-10-01", 365)
+            .unwrap_err()
+            .contains("after"));
+        assert!(range("2026-10-01", "2026-10-10", 9)
+            .unwrap_err()
+            .contains("At most 9"));
+        assert!(range("2026-10-01", "2026-10-09", 9).is_ok());
+        assert!(range("17/10", "2026-10-18", 365)
+            .unwrap_err()
+            .contains("Invalid date 17/10"));
+    }
+
+    /// Two workers in front of a Telldus gateway that accepts connections but never answers
+    fn hung_telldus_server() -> String {
+        let telldus = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = config();
+        config.telldus_ip = telldus.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let _held: Vec<_> = telldus.incoming().collect();
+        });
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let context = Arc::new(Context {
+            data: Arc::new(Mutex::new(structs::AppState {
+                config: config.clone(),
+                devices: toml::from_str("device = []").unwrap(),
+                todays_spot_prices: serde_json::Value::Array(vec![]),
+                tomorrows_spot_prices: serde_json::Value::Array(vec![]),
+                stats: Default::default(),
+                fallback: false,
+            })),
+            devices: Mutex::new(toml::from_str("device = []").unwrap()),
+            config,
+        });
+        spawn_workers(server, 2, &context);
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn slow_requests_time_out_without_blocking_the_workers() {
+        let base = hung_telldus_server();
+        let get = |url: String| reqwest::blocking::get(url).unwrap().status().as_u16();
+
+        // tiny_http reads a burst of new connections on its idle threads only, so space them
+        let slow: Vec<_> = (0..MAX_SLOW + 1)
+            .map(|_| {
+                let url = format!("{}/listdevices", base);
+                thread::sleep(REQUEST_TIMEOUT / 50);
+                thread::spawn(move || get(url))
+            })
+            .collect();
+        // Every slow call is offloaded, so both workers still answer at once
+        thread::sleep(REQUEST_TIMEOUT / 10);
+        let started = Instant::now();
+        assert_eq!(get(format!("{}/health", base)), 200);
+        assert!(started.elapsed() < REQUEST_TIMEOUT / 2);
+
+        let mut codes: Vec<u16> = slow.into_iter().map(|t| t.join().unwrap()).collect();
+        codes.sort();
+        // One too many is turned away, the others give up waiting for Telldus
+        let mut expected = vec![503];
+        expected.extend([504; MAX_SLOW]);
+        assert_eq!(codes, expected);
     }
 }
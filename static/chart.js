@@ -0,0 +1,267 @@
+// Small canvas bar and line charts with the part of the Chart.js API the dashboard uses:
+// new Chart(canvas, { type, data: { labels, datasets }, options }), chart.data,
+// chart.update() and chart.destroy(). Embedded in the binary so the web UI needs no internet.
+
+const CHART_PALETTE = ["#888", "#555", "#c44", "#4c4", "#48c", "#cc4"];
+const CHART_TEXT = "#999";
+const CHART_GRID = "rgba(255, 255, 255, 0.1)";
+
+class Chart {
+  constructor(canvas, config) {
+    this.canvas = canvas;
+    this.ctx = canvas.getContext("2d");
+    this.type = config.type || "bar";
+    this.data = config.data || { labels: [], datasets: [] };
+    this.options = config.options || {};
+    this.hover = -1;
+
+    this.onMove = (e) => {
+      const index = this.indexAt(e.offsetX, e.offsetY);
+      if (index !== this.hover) {
+        this.hover = index;
+        this.draw();
+      }
+    };
+    this.onLeave = () => {
+      this.hover = -1;
+      this.draw();
+    };
+    canvas.addEventListener("mousemove", this.onMove);
+    canvas.addEventListener("mouseleave", this.onLeave);
+
+    canvas.style.display = "block";
+    this.observer = new ResizeObserver(() => this.update());
+    this.observer.observe(canvas.parentElement);
+
+    this.update();
+  }
+
+  destroy() {
+    this.canvas.removeEventListener("mousemove", this.onMove);
+    this.canvas.removeEventListener("mouseleave", this.onLeave);
+    this.observer.disconnect();
+    this.ctx.clearRect(0, 0, this.canvas.width, this.canvas.height);
+  }
+
+  update() {
+    const parent = this.canvas.parentElement;
+    this.width = parent.clientWidth;
+    this.height = parent.clientHeight || Number(this.canvas.getAttribute("height")) || 300;
+
+    const dpr = window.devicePixelRatio || 1;
+    this.canvas.width = Math.round(this.width * dpr);
+    this.canvas.height = Math.round(this.height * dpr);
+    this.canvas.style.width = `${this.width}px`;
+    this.canvas.style.height = `${this.height}px`;
+    this.ctx.setTransform(dpr, 0, 0, dpr, 0, 0);
+
+    this.draw();
+  }
+
+  // Font size from the Chart.js style options, 12 by default
+  fontSize(path) {
+    let value = this.options;
+    for (const key of path) value = value ? value[key] : undefined;
+    return value || 12;
+  }
+
+  font(path) {
+    return `${this.fontSize(path)}px system-ui, sans-serif`;
+  }
+
+  color(dataset, index, j) {
+    const hovered = index === this.hover && dataset.hoverBackgroundColor;
+    const color = hovered
+      ? dataset.hoverBackgroundColor
+      : this.type === "line"
+        ? dataset.borderColor || dataset.backgroundColor
+        : dataset.backgroundColor;
+    if (Array.isArray(color)) return color[index] || CHART_PALETTE[j % CHART_PALETTE.length];
+    return color || CHART_PALETTE[j % CHART_PALETTE.length];
+  }
+
+  // Axis range rounded to a readable step
+  scale() {
+    const values = this.data.datasets
+      .flatMap((ds) => ds.data)
+      .filter((v) => typeof v === "number" && isFinite(v));
+    let min = Math.min(0, ...values);
+    let max = Math.max(0, ...values);
+    if (min === max) max = min + 1;
+
+    const rough = (max - min) / 5;
+    const magnitude = Math.pow(10, Math.floor(Math.log10(rough)));
+    const step = [1, 2, 5, 10].map((m) => m * magnitude).find((s) => s >= rough);
+
+    return {
+      min: Math.floor(min / step) * step,
+      max: Math.ceil(max / step) * step,
+      step,
+      decimals: Math.max(0, -Math.floor(Math.log10(step))),
+    };
+  }
+
+  // Index of the label under the mouse, or -1
+  indexAt(x, y) {
+    const area = this.area;
+    const count = this.data.labels.length;
+    if (!area || count === 0) return -1;
+    if (x < area.left || x > area.right || y < area.top || y > area.bottom) return -1;
+    return Math.min(count - 1, Math.floor((x - area.left) / ((area.right - area.left) / count)));
+  }
+
+  draw() {
+    const ctx = this.ctx;
+    const { labels, datasets } = this.data;
+    ctx.clearRect(0, 0, this.width, this.height);
+    if (!this.width || !this.height || labels.length === 0) return;
+
+    const scale = this.scale();
+    const tickFont = this.font(["scales", "y", "ticks", "font", "size"]);
+    const labelFont = this.font(["scales", "x", "ticks", "font", "size"]);
+    const legendSize = this.fontSize(["plugins", "legend", "labels", "font", "size"]);
+
+    // Layout: legend on top, y ticks to the left, x labels below
+    ctx.font = tickFont;
+    let tickWidth = 0;
+    for (let v = scale.min; v <= scale.max + scale.step / 2; v += scale.step) {
+      tickWidth = Math.max(tickWidth, ctx.measureText(v.toFixed(scale.decimals)).width);
+    }
+    const area = {
+      left: tickWidth + 12,
+      right: this.width - 8,
+      top: legendSize + 20,
+      bottom: this.height - this.fontSize(["scales", "x", "ticks", "font", "size"]) - 12,
+    };
+    this.area = area;
+
+    const y = (v) =>
+      area.bottom - ((v - scale.min) / (scale.max - scale.min)) * (area.bottom - area.top);
+    const band = (area.right - area.left) / labels.length;
+
+    // Grid and y ticks
+    ctx.textAlign = "right";
+    ctx.textBaseline = "middle";
+    ctx.strokeStyle = CHART_GRID;
+    ctx.fillStyle = CHART_TEXT;
+    ctx.lineWidth = 1;
+    for (let v = scale.min; v <= scale.max + scale.step / 2; v += scale.step) {
+      ctx.beginPath();
+      ctx.moveTo(area.left, Math.round(y(v)) + 0.5);
+      ctx.lineTo(area.right, Math.round(y(v)) + 0.5);
+      ctx.stroke();
+      ctx.fillText(v.toFixed(scale.decimals), area.left - 6, y(v));
+    }
+
+    // X labels, skipping some when they would overlap
+    ctx.font = labelFont;
+    ctx.textAlign = "center";
+    ctx.textBaseline = "top";
+    const labelWidth = Math.max(...labels.map((l) => ctx.measureText(String(l ?? "")).width));
+    const every = Math.max(1, Math.ceil((labelWidth + 8) / band));
+    labels.forEach((label, i) => {
+      if (i % every === 0) {
+        ctx.fillText(String(label ?? ""), area.left + band * (i + 0.5), area.bottom + 6);
+      }
+    });
+
+    // Data
+    const zero = y(Math.max(scale.min, Math.min(0, scale.max)));
+    if (this.type === "line") {
+      datasets.forEach((ds, j) => {
+        ctx.strokeStyle = this.color(ds, -1, j);
+        ctx.lineWidth = 2;
+        ctx.beginPath();
+        let drawing = false;
+        ds.data.forEach((v, i) => {
+          if (typeof v !== "number") {
+            drawing = false;
+            return;
+          }
+          const x = area.left + band * (i + 0.5);
+          if (drawing) ctx.lineTo(x, y(v));
+          else ctx.moveTo(x, y(v));
+          drawing = true;
+        });
+        ctx.stroke();
+      });
+    } else {
+      const width = (band * 0.8) / datasets.length;
+      datasets.forEach((ds, j) => {
+        ds.data.forEach((v, i) => {
+          if (typeof v !== "number") return;
+          const x = area.left + band * i + band * 0.1 + width * j;
+          ctx.fillStyle = this.color(ds, i, j);
+          ctx.fillRect(x, Math.min(zero, y(v)), Math.max(width - 1, 1), Math.abs(y(v) - zero));
+        });
+      });
+    }
+
+    this.drawLegend(legendSize);
+    if (this.hover !== -1) this.drawTooltip(this.hover, band);
+  }
+
+  drawLegend(size) {
+    const ctx = this.ctx;
+    ctx.font = `${size}px system-ui, sans-serif`;
+    ctx.textAlign = "left";
+    ctx.textBaseline = "middle";
+
+    const items = this.data.datasets.map((ds, j) => ({
+      label: ds.label || "",
+      color: Array.isArray(ds.backgroundColor) && this.type !== "line"
+        ? CHART_PALETTE[0]
+        : this.color(ds, -1, j),
+    }));
+    const widths = items.map((item) => size * 2 + ctx.measureText(item.label).width + 16);
+    let x = (this.width - widths.reduce((a, b) => a + b, 0)) / 2;
+
+    items.forEach((item, j) => {
+      ctx.fillStyle = item.color;
+      ctx.fillRect(x, 4, size * 2, size);
+      ctx.fillStyle = CHART_TEXT;
+      ctx.fillText(item.label, x + size * 2 + 6, 4 + size / 2);
+      x += widths[j];
+    });
+  }
+
+  drawTooltip(index, band) {
+    const ctx = this.ctx;
+    const area = this.area;
+    const size = this.fontSize(["plugins", "tooltip", "bodyFont", "size"]);
+    ctx.font = `${size}px system-ui, sans-serif`;
+
+    const lines = [String(this.data.labels[index] ?? "")];
+    const colors = [null];
+    this.data.datasets.forEach((ds, j) => {
+      const v = ds.data[index];
+      if (typeof v !== "number") return;
+      lines.push(`${ds.label || ""}: ${v}`);
+      colors.push(this.color(ds, index, j));
+    });
+    if (lines.length === 1) return;
+
+    const lineHeight = size + 6;
+    const width = Math.max(...lines.map((l) => ctx.measureText(l).width)) + size + 24;
+    const height = lines.length * lineHeight + 8;
+    const center = area.left + band * (index + 0.5);
+    const x = center + width + 12 > area.right ? center - width - 12 : center + 12;
+    const top = area.top + 4;
+
+    ctx.fillStyle = "rgba(0, 0, 0, 0.8)";
+    ctx.fillRect(x, top, width, height);
+    ctx.textAlign = "left";
+    ctx.textBaseline = "middle";
+    lines.forEach((line, i) => {
+      const ly = top + 4 + lineHeight * (i + 0.5);
+      let lx = x + 8;
+      if (colors[i]) {
+        ctx.fillStyle = colors[i];
+        ctx.fillRect(lx, ly - size / 2, size, size);
+        lx += size + 6;
+      }
+      ctx.fillStyle = "#fff";
+      ctx.fillText(line, lx, ly);
+    });
+  }
+}
@@ -1,4 +1,24 @@
 let chartRef = null;
+let currentIndex = -1;
+
+// Text from the config, e.g. a device name or rule, made safe for innerHTML and attributes
+function escapeHtml(text) {
+  return String(text)
+    .replaceAll("&", "&amp;")
+    .replaceAll("<", "&lt;")
+    .replaceAll(">", "&gt;")
+    .replaceAll('"', "&quot;")
+    .replaceAll("'", "&#39;");
+}
+
+// Index of the slot covering the current time, or -1
+function currentSlotIndex(slots) {
+  const now = new Date();
+  return slots.findIndex(
+    (item) =>
+      new Date(item.time_start) <= now && now < new Date(item.time_end),
+  );
+}
 
 async function priceChart() {
   const res = await fetch("/today");
@@ -26,16 +46,12 @@ async function priceChart() {
   const labelsToday = data.map((item) => item.time_start.slice(11, 16));
   const valuesToday = data.map((item) => item[currencyKey]);
 
-  // Compute current 15-min slot
-  const now = new Date();
-  const hours = now.getHours().toString().padStart(2, "0");
-  const minutes = Math.floor(now.getMinutes() / 15) * 15;
-  const mins = minutes.toString().padStart(2, "0");
-  const currentLabel = `${hours}:${mins}`;
+  // Find the current slot by time, works for any slot length
+  currentIndex = currentSlotIndex(data);
 
   // Base colors for today
-  const baseColorsToday = labelsToday.map((l) =>
-    l === currentLabel ? "orange" : "#888",
+  const baseColorsToday = labelsToday.map((_, i) =>
+    i === currentIndex ? "orange" : "#888",
   );
 
   // Default merged labels and dataset
@@ -131,46 +147,104 @@ async function priceChart() {
       },
     },
   });
-  // Find current price
-  let currentPrice = null;
-  const idx = labelsToday.indexOf(currentLabel);
-  if (idx !== -1) {
-    currentPrice = valuesToday[idx];
+  priceCards();
+  deviceList();
+  planStrip();
+}
+
+// Gantt strip of the planned On/Off spans of every device, under the price chart
+async function planStrip() {
+  const el = document.getElementById("plan");
+  if (!el) return;
+
+  const res = await fetch("/plan");
+  if (!res.ok) return;
+  const plan = await res.json();
+
+  const spans = plan.devices.flatMap((d) => d.spans);
+  if (spans.length === 0) {
+    el.innerHTML = "";
+    return;
+  }
+  const start = Math.min(...spans.map((s) => new Date(s.start).getTime()));
+  const end = Math.max(...spans.map((s) => new Date(s.end).getTime()));
+  const percent = (t) => (100 * (t - start)) / (end - start);
+  const colors = { On: "green", Off: "#552222", Unknown: "gray" };
+  const time = (t) => t.slice(11, 16);
+
+  const now = Date.now();
+  const marker =
+    now >= start && now < end
+      ? `<div class="plan-now" style="left:${percent(now)}%"></div>`
+      : "";
+
+  let html = "";
+  for (const device of plan.devices) {
+    html += `<div class="plan-row"><div class="plan-name">${escapeHtml(device.name)}</div><div class="plan-strip">`;
+    for (const span of device.spans) {
+      const left = percent(new Date(span.start).getTime());
+      const width = percent(new Date(span.end).getTime()) - left;
+      const title = `${span.state} ${time(span.start)}-${time(span.end)}, ${span.reason}, ${span.spot.toFixed(2)} ${plan.currency}`;
+      html += `<div class="plan-span" style="left:${left}%;width:${width}%;background:${colors[span.state]}" title="${escapeHtml(title)}"></div>`;
+    }
+    html += `${marker}</div></div>`;
   }
+  el.innerHTML = html;
+}
+
+// Price cards from the server's summary, "unknown" when there is no price
+async function priceCards() {
+  const res = await fetch("/summary");
+  const summary = await res.json();
 
-  // Update the DOM
-  const totalPrice =
-    (currentPrice +
-      config.grid_fee +
-      config.energy_tax +
-      config.variable_costs +
-      config.spot_fee +
-      config.cert_fee) *
-    (1 + config.vat);
+  const fmt = (v) => (typeof v === "number" ? v.toFixed(4) : "unknown");
+  const time = (t) => (t ? t.slice(11, 16) : "");
+
+  const cards = [
+    `Spot price: <strong>${fmt(summary.current?.spot)}</strong>`,
+    `Total price: <strong>${fmt(summary.current?.total)}</strong>`,
+  ];
+  if (summary.today) {
+    cards.push(
+      `Today: <strong>${fmt(summary.today.avg)}</strong> avg<br>` +
+        `${fmt(summary.today.min)} – ${fmt(summary.today.max)}`,
+    );
+  }
+  if (summary.cheapest_window) {
+    const w = summary.cheapest_window;
+    cards.push(
+      `Cheapest ${summary.window_hours} h: <strong>${time(w.start)}–${time(w.end)}</strong><br>` +
+        `${fmt(w.spot)} spot`,
+    );
+  }
+  if (!summary.tomorrow_available) {
+    cards.push("Tomorrow's prices not yet available");
+  }
 
   const container = document.getElementById("prices");
   container.innerHTML = "";
-  const spot = document.createElement("div");
-  spot.className = "price-card";
-  spot.innerHTML = `
-            Spot price: <strong>${currentPrice.toFixed(4)}</strong> <br>
-          `;
-  container.appendChild(spot);
-  const total = document.createElement("div");
-  total.className = "price-card";
-  total.innerHTML = `
-            Total price: <strong>${totalPrice.toFixed(4)}</strong> <br>
-          `;
-  container.appendChild(total);
-  deviceList();
+  for (const html of cards) {
+    const card = document.createElement("div");
+    card.className = "price-card";
+    card.innerHTML = html;
+    container.appendChild(card);
+  }
 }
 
+// The config the device cards depend on, fetched along with the devices
+let deviceConfig = {};
+
 async function deviceList() {
   const res = await fetch("/devices");
   const json = await res.json();
   const res2 = await fetch("/config");
-  const config = await res2.json();
+  deviceConfig = await res2.json();
+  renderDevices(json);
+}
 
+// Draw the device cards, from /devices or a devices event
+function renderDevices(json) {
+  const config = deviceConfig;
   const container = document.getElementById("devices");
   container.innerHTML = "";
 
@@ -191,7 +265,7 @@ async function deviceList() {
 
     if (d.mode === "Price") {
       html = `
-    <span class="state ${stateClass}"><strong>${d.name}</strong></span><br>
+    <span class="state ${stateClass}"><strong>${escapeHtml(d.name)}</strong></span><br>
     Mode: ${d.mode}<br>
     Telldus: ${d.telldus}<br>
     Price: ${d.price}<br>
@@ -199,15 +273,30 @@ async function deviceList() {
   `;
     } else if (d.mode === "Ratio") {
       html = `
-    <span class="state ${stateClass}"><strong>${d.name}</strong></span><br>
+    <span class="state ${stateClass}"><strong>${escapeHtml(d.name)}</strong></span><br>
     Mode: ${d.mode}<br>
     Telldus: ${d.telldus}<br>
     <s>Price: ${d.price}</s><br>
     Ratio: ${d.ratio}<br>
+  `;
+    } else if (d.mode === "Average") {
+      html = `
+    <span class="state ${stateClass}"><strong>${escapeHtml(d.name)}</strong></span><br>
+    Mode: ${d.mode}<br>
+    Telldus: ${d.telldus}<br>
+    Relative: ${d.relative} of ${d.reference}<br>
+    Trigger: ${d.today_trigger_price.toFixed(4)}<br>
+  `;
+    } else if (d.mode === "Rule") {
+      html = `
+    <span class="state ${stateClass}"><strong>${escapeHtml(d.name)}</strong></span><br>
+    Mode: ${d.mode}<br>
+    Telldus: ${d.telldus}<br>
+    On when: <code>${escapeHtml(d.on_when)}</code><br>
   `;
     } else {
       html = `
-    <span class="state ${stateClass}"><strong>${d.name}</strong></span><br>
+    <span class="state ${stateClass}"><strong>${escapeHtml(d.name)}</strong></span><br>
     Mode: ${d.mode}<br>
     <s>Telldus: ${d.telldus}</s><br>
     <s>Price: ${d.price}</s><br>
@@ -215,6 +304,14 @@ async function deviceList() {
   `;
     }
 
+    if (d.suppressed) {
+      html += `<span class="suppressed">${escapeHtml(d.suppressed)}</span><br>`;
+    }
+
+    if (d.dry_run || config.dry_run) {
+      html += `<span class="suppressed">Dry run, not switched</span><br>`;
+    }
+
     if (config.webui_toggle === true) {
       html += `<button class="switch-on">On</button> <button class="switch-off">Off</button>`;
     }
@@ -284,15 +381,9 @@ document.addEventListener("mouseout", (e) => {
 
   const labels = chartRef.data.labels;
 
-  const now = new Date();
-  const hours = now.getHours().toString().padStart(2, "0");
-  const minutes = Math.floor(now.getMinutes() / 15) * 15;
-  const mins = minutes.toString().padStart(2, "0");
-  const currentLabel = `${hours}:${mins}`;
-
   // Reset TODAY
-  chartRef.data.datasets[0].backgroundColor = labels.map((l) =>
-    l === currentLabel ? "orange" : "#888",
+  chartRef.data.datasets[0].backgroundColor = labels.map((_, i) =>
+    i === currentIndex ? "orange" : "#888",
   );
 
   // Reset TOMORROW only if present
@@ -304,6 +395,97 @@ document.addEventListener("mouseout", (e) => {
   chartRef.update();
 });
 
+async function statsTable() {
+  const res = await fetch("/stats");
+  const stats = await res.json();
+
+  const day = isoDate(new Date());
+  const month = day.slice(0, 7);
+  const empty = { kwh: 0, total_cost: 0, savings: 0 };
+
+  let rows = "";
+  for (const [name, d] of Object.entries(stats.devices)) {
+    const t = d.days[day] || empty;
+    const m = d.months[month] || empty;
+    rows += `<tr>
+      <td>${escapeHtml(name)}</td>
+      <td>${t.kwh.toFixed(2)}</td><td>${t.total_cost.toFixed(2)}</td><td>${t.savings.toFixed(2)}</td>
+      <td>${m.kwh.toFixed(2)}</td><td>${m.total_cost.toFixed(2)}</td><td>${m.savings.toFixed(2)}</td>
+    </tr>`;
+  }
+
+  document.getElementById("stats").innerHTML = `
+    <table>
+      <tr><th></th><th colspan="3">Today</th><th colspan="3">This month</th></tr>
+      <tr><th>Device</th><th>kWh</th><th>Cost</th><th>Saved</th><th>kWh</th><th>Cost</th><th>Saved</th></tr>
+      ${rows}
+    </table>`;
+}
+
+let historyChartRef = null;
+
+// YYYY-MM-DD of a date in local time
+function isoDate(d) {
+  return `${d.getFullYear()}-${(d.getMonth() + 1).toString().padStart(2, "0")}-${d.getDate().toString().padStart(2, "0")}`;
+}
+
+async function historyView() {
+  const from = document.getElementById("history-from").value;
+  const to = document.getElementById("history-to").value;
+
+  const res = await fetch(`/prices?from=${from}&to=${to}`);
+  const days = await res.json();
+  if (!res.ok || !Array.isArray(days)) return;
+
+  // Daily min/avg/max
+  document.getElementById("HistoryChart").style.display = "block";
+  if (historyChartRef) historyChartRef.destroy();
+  historyChartRef = new Chart(document.getElementById("historyChart"), {
+    type: "line",
+    data: {
+      labels: days.map((d) => d.date),
+      datasets: [
+        { label: "Max", data: days.map((d) => d.max), borderColor: "#c44" },
+        { label: "Average", data: days.map((d) => d.avg), borderColor: "#ccc" },
+        { label: "Min", data: days.map((d) => d.min), borderColor: "#4c4" },
+      ],
+    },
+    options: { maintainAspectRatio: false, responsive: true, animation: false },
+  });
+
+  // Heatmap, hour of day vs date
+  const values = days.flatMap((d) => d.hours).filter((v) => v !== null);
+  const min = Math.min(...values);
+  const max = Math.max(...values);
+  const color = (v) => {
+    if (v === null) return "#222";
+    const t = max > min ? (v - min) / (max - min) : 0;
+    return `hsl(${Math.round(120 - 120 * t)}, 70%, 40%)`;
+  };
+
+  let html = "<table><tr><th></th>";
+  for (let h = 0; h < 24; h++) html += `<th>${h}</th>`;
+  html += "</tr>";
+  for (const d of days) {
+    html += `<tr><th>${d.date}</th>`;
+    for (const v of d.hours) {
+      const title = v === null ? "" : v.toFixed(4);
+      html += `<td style="background:${color(v)}" title="${escapeHtml(title)}"></td>`;
+    }
+    html += "</tr>";
+  }
+  document.getElementById("heatmap").innerHTML = html + "</table>";
+}
+
+function setupHistory() {
+  const to = new Date();
+  const from = new Date();
+  from.setDate(from.getDate() - 30);
+  document.getElementById("history-from").value = isoDate(from);
+  document.getElementById("history-to").value = isoDate(to);
+  document.getElementById("history-show").addEventListener("click", historyView);
+}
+
 async function checkBackendHealth() {
   const el = document.getElementById("health");
   if (!el) return;
@@ -315,8 +497,18 @@ async function checkBackendHealth() {
     const res = await fetch("/health", { signal: controller.signal });
     clearTimeout(timeout);
 
-    if (res.ok) {
+    const health = res.ok ? await res.json() : null;
+
+    const banner = document.getElementById("fallback");
+    if (banner) banner.hidden = !(health && health.fallback);
+
+    if (health && health.status === "degraded") {
+      el.textContent = "Price download failing ⚠️";
+      el.title = health.fetch.last_error || "";
+      el.style.color = "orange";
+    } else if (res.ok) {
       el.textContent = "Connected ✔️";
+      el.title = "";
       el.style.color = "green";
     } else {
       el.textContent = "Disconnected ❌";
@@ -330,14 +522,41 @@ async function checkBackendHealth() {
   }
 }
 
+// Live updates pushed by the server, the timers below only catch what was missed
+function listenEvents() {
+  const source = new EventSource("/events");
+  // A manual switch, the control loop sends the whole list with devices
+  source.addEventListener("device", () => deviceList());
+  source.addEventListener("devices", (e) => renderDevices(JSON.parse(e.data)));
+  source.addEventListener("slot", () => priceChart());
+  source.addEventListener("fetch", (e) => {
+    if (JSON.parse(e.data).ok) priceChart();
+    checkBackendHealth();
+  });
+  // Catch up on what changed while disconnected
+  source.onopen = () => {
+    deviceList();
+    checkBackendHealth();
+  };
+  source.onerror = checkBackendHealth;
+}
+listenEvents();
+
 // Refresh price chart
-setInterval(priceChart, 120 * 1000);
+setInterval(priceChart, 10 * 60 * 1000);
 priceChart();
 
-// Refresh device list
-setInterval(deviceList, 5 * 1000);
+// Refresh device list, in case events are missed
+setInterval(deviceList, 5 * 60 * 1000);
 deviceList();
 
+// Refresh energy and cost table
+setInterval(statsTable, 60 * 1000);
+statsTable();
+
+// History on request
+document.addEventListener("DOMContentLoaded", setupHistory);
+
 // Check server health
-setInterval(checkBackendHealth, 3 * 1000);
+setInterval(checkBackendHealth, 30 * 1000);
 checkBackendHealth();